@@ -5,4 +5,10 @@
   inputs = { };
   extraTrustedPublicKeys = [ ];
   extraSubstituters = [ ];
+  hooks = [
+    {
+      description = "Allow direnv to load the generated .envrc file.";
+      command = "direnv allow";
+    }
+  ];
 }
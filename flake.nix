@@ -27,6 +27,10 @@
         NOTE: It is required to pass an instance of your `stdenv` to this 
         function.
 
+        Optionally, pass `presets` pointing to a directory of `<name>.nix`
+        files, each evaluating to `{ description, parts }`, to ship named
+        collections of parts usable as `@name`.
+
         *Type*: `mkFlakeParts :: Attrset a -> Package a`
       */
       mkFlakeParts =
@@ -43,6 +47,10 @@
             installPhase = ''
               mkdir -p $out/flake-parts
               cp -rv $src/* $out/flake-parts
+              if [ -n "''${presets:-}" ]; then
+                mkdir -p $out/presets
+                cp -rv $presets/* $out/presets
+              fi
             '';
           } // args;
         in
@@ -0,0 +1,849 @@
+//! Planning and applying of changes from a prepared directory (usually
+//! the tmpdir with the new project) to the target directory.
+use color_eyre::eyre::Result;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use thiserror::Error;
+use walkdir::WalkDir;
+
+use crate::fs_utils::{is_symlink, normalize_path, read_entry, relative_path, unified_diff};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChangeKind {
+    /// The file doesn't exist in the target directory yet
+    Create,
+
+    /// The file already exists in the target directory and will be replaced
+    Modify,
+
+    /// The file exists in the target directory and will be removed
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileChange {
+    /// Path relative to the target directory
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    pub contents: Vec<u8>,
+
+    /// Unix permissions of the written file
+    pub mode: u32,
+
+    /// Relative target in case the file is a symlink, `contents` then
+    /// hold the target as well
+    pub symlink: Option<PathBuf>,
+}
+
+#[derive(Error, Debug)]
+pub enum PatchError {
+    #[error("{0:?} is a binary file which can't be represented in a patch, apply the changes directly instead")]
+    BinaryFileError(PathBuf),
+}
+
+#[derive(Error, Debug)]
+pub enum SymlinkError {
+    #[error("Symlink {0:?} points to {1:?} outside of its part, which isn't an existing regular file that could be copied instead")]
+    UnresolvableLinkError(PathBuf, PathBuf),
+}
+
+impl FileChange {
+    pub fn new(path: PathBuf, kind: ChangeKind, contents: Vec<u8>) -> Self {
+        Self {
+            path,
+            kind,
+            contents,
+            mode: 0o644,
+            symlink: None,
+        }
+    }
+
+    /// Change writing the file (or symlink) at `source` as it is, without
+    /// following symlinks.
+    pub fn from_entry(path: PathBuf, kind: ChangeKind, source: &Path) -> Result<Self> {
+        let change = Self::new(path, kind, read_entry(source)?);
+        Ok(match is_symlink(source) {
+            true => change.with_mode(0o777).with_symlink(fs::read_link(source)?),
+            false => change.with_mode(mode_of(source)?),
+        })
+    }
+
+    pub fn with_symlink(mut self, target: PathBuf) -> Self {
+        self.symlink = Some(target);
+        self
+    }
+
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+fn mode_of(path: &Path) -> Result<u32> {
+    Ok(fs::metadata(path)?.permissions().mode() & 0o7777)
+}
+
+/// Resolves a symlink found in `root`. Links pointing inside of `root`
+/// are kept as relative links (eg. absolute links into the nix store would
+/// break once the store path gets garbage collected), links pointing
+/// outside of it are replaced by a copy of the file they point to.
+fn resolve_symlink(root: &Path, link: &Path) -> Result<(Vec<u8>, u32, Option<PathBuf>)> {
+    let link_target = fs::read_link(link)?;
+    let parent = link.parent().unwrap_or(root);
+    let resolved = normalize_path(&parent.join(&link_target));
+
+    if resolved.starts_with(normalize_path(root)) {
+        let relative_target = relative_path(&normalize_path(parent), &resolved);
+        let contents = relative_target.as_os_str().as_bytes().to_vec();
+        return Ok((contents, 0o777, Some(relative_target)));
+    }
+
+    if !resolved.is_file() {
+        Err(SymlinkError::UnresolvableLinkError(
+            link.to_path_buf(),
+            link_target,
+        ))?
+    }
+
+    log::warn!(
+        "Symlink {:?} points outside of its part, copying {:?} instead",
+        link,
+        resolved
+    );
+    Ok((fs::read(&resolved)?, mode_of(&resolved)?, None))
+}
+
+/// Compares all files in `src` with their counterparts in `dst` and plans
+/// the necessary changes. Files that already exist in `dst` and differ are
+/// passed to `resolve` together with both versions (`ours` being the one
+/// in `dst`), which decides the new content or skips the file by returning
+/// `None`. Already existing files keep their mode unless a mode is
+/// declared for them in `declared_modes`.
+pub fn plan_changes<F>(
+    src: &Path,
+    dst: &Path,
+    declared_modes: &BTreeMap<PathBuf, u32>,
+    mut resolve: F,
+) -> Result<Vec<FileChange>>
+where
+    F: FnMut(&Path, &[u8], &[u8]) -> Result<Option<Vec<u8>>>,
+{
+    let mut changes = Vec::new();
+
+    for entry in WalkDir::new(src).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+
+        let path = entry.path().strip_prefix(src)?.to_path_buf();
+        let target_path = dst.join(&path);
+        let (theirs, mode, symlink) = if entry.path_is_symlink() {
+            resolve_symlink(src, entry.path())?
+        } else {
+            (fs::read(entry.path())?, mode_of(entry.path())?, None)
+        };
+
+        let with_symlink = |change: FileChange| match &symlink {
+            Some(target) => change.with_symlink(target.clone()),
+            None => change,
+        };
+
+        if target_path.symlink_metadata().is_err() {
+            changes.push(with_symlink(
+                FileChange::new(path, ChangeKind::Create, theirs).with_mode(mode),
+            ));
+            continue;
+        }
+
+        let ours = read_entry(&target_path)?;
+        let unchanged = ours == theirs && is_symlink(&target_path) == symlink.is_some();
+        if unchanged && (symlink.is_some() || !declared_modes.contains_key(&path)) {
+            continue;
+        }
+
+        let resolved = match unchanged {
+            true => Some(theirs.clone()),
+            false => resolve(&path, &ours, &theirs)?,
+        };
+
+        match resolved {
+            // NOTE symlinks can only be replaced, never merged
+            Some(contents) if symlink.is_some() && contents != theirs => {
+                log::warn!("Symlink {:?} can't be merged, leaving it untouched", path)
+            }
+            Some(contents) => {
+                let current_mode = match symlink {
+                    Some(_) => mode,
+                    None => mode_of(&target_path)?,
+                };
+                let mode = match (&symlink, declared_modes.get(&path)) {
+                    (None, Some(&declared)) => declared,
+                    _ => current_mode,
+                };
+
+                if contents != ours || symlink.is_some() || mode != current_mode {
+                    changes.push(with_symlink(
+                        FileChange::new(path, ChangeKind::Modify, contents).with_mode(mode),
+                    ));
+                } else {
+                    log::debug!("Leaving already existing file {:?} untouched", target_path);
+                }
+            }
+            None => log::debug!("Leaving already existing file {:?} untouched", target_path),
+        }
+    }
+
+    Ok(changes)
+}
+
+/// A single step of an applied transaction, used for the rollback.
+#[derive(Debug)]
+enum AppliedStep {
+    CreatedDir(PathBuf),
+    CreatedFile(PathBuf),
+    ReplacedFile { path: PathBuf, backup: PathBuf },
+}
+
+/// Applies changes to a directory in two stages. All new contents are
+/// first written into a staging directory and only then moved into place,
+/// while the replaced files are kept as backups. In case of a failure
+/// everything is rolled back, leaving the directory exactly as it was.
+struct Transaction {
+    dst: PathBuf,
+    // NOTE the staging directory lives inside of `dst` so that all
+    // of the moves are just cheap renames on the same filesystem
+    staging: TempDir,
+    applied: Vec<AppliedStep>,
+}
+
+impl Transaction {
+    fn begin(dst: &Path) -> Result<Self> {
+        fs::create_dir_all(dst)?;
+        let staging = tempfile::Builder::new()
+            .prefix(".flake-parts-builder-staging")
+            .tempdir_in(dst)?;
+
+        Ok(Self {
+            dst: dst.to_path_buf(),
+            staging,
+            applied: Vec::new(),
+        })
+    }
+
+    fn staged_path(&self, change: &FileChange) -> PathBuf {
+        self.staging.path().join("new").join(&change.path)
+    }
+
+    fn stage(&self, change: &FileChange) -> Result<()> {
+        if change.kind == ChangeKind::Delete {
+            return Ok(());
+        }
+
+        let staged_path = self.staged_path(change);
+        if let Some(parent) = staged_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if let Some(target) = &change.symlink {
+            symlink(target, &staged_path)?;
+            return Ok(());
+        }
+
+        fs::write(&staged_path, &change.contents)?;
+        fs::set_permissions(&staged_path, fs::Permissions::from_mode(change.mode))?;
+        Ok(())
+    }
+
+    fn create_parent_dirs(&mut self, path: &Path) -> Result<()> {
+        let mut missing = path
+            .ancestors()
+            .skip(1)
+            .take_while(|ancestor| *ancestor != self.dst && !ancestor.exists())
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        missing.reverse();
+
+        for dir in missing {
+            fs::create_dir(&dir)?;
+            self.applied.push(AppliedStep::CreatedDir(dir));
+        }
+        Ok(())
+    }
+
+    /// Moves the current version of the file out of the way, it's moved
+    /// back in case of a rollback.
+    fn backup(&mut self, change: &FileChange, target_path: &Path) -> Result<()> {
+        let backup = self.staging.path().join("backup").join(&change.path);
+        if let Some(parent) = backup.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(target_path, &backup)?;
+        self.applied.push(AppliedStep::ReplacedFile {
+            path: target_path.to_path_buf(),
+            backup,
+        });
+        Ok(())
+    }
+
+    fn commit(&mut self, change: &FileChange) -> Result<()> {
+        let target_path = self.dst.join(&change.path);
+        log::debug!("Applying {:?} change to {:?}", change.kind, target_path);
+
+        if change.kind == ChangeKind::Delete {
+            if target_path.symlink_metadata().is_ok() {
+                self.backup(change, &target_path)?;
+            }
+            return Ok(());
+        }
+
+        self.create_parent_dirs(&target_path)?;
+
+        if target_path.symlink_metadata().is_ok() {
+            self.backup(change, &target_path)?;
+            fs::rename(self.staged_path(change), &target_path)?;
+        } else {
+            fs::rename(self.staged_path(change), &target_path)?;
+            self.applied.push(AppliedStep::CreatedFile(target_path));
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        log::warn!("Rolling back {} applied steps", self.applied.len());
+
+        for step in self.applied.drain(..).rev() {
+            log::debug!("Rolling back {:?}", step);
+            match step {
+                AppliedStep::CreatedDir(dir) => fs::remove_dir(dir)?,
+                AppliedStep::CreatedFile(path) => fs::remove_file(path)?,
+                AppliedStep::ReplacedFile { path, backup } => {
+                    if path.symlink_metadata().is_ok() {
+                        fs::remove_file(&path)?;
+                    }
+                    fs::rename(backup, path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Transactionally applies all of the `changes` to `dst`, see [`Transaction`].
+pub fn apply_changes(dst: &Path, changes: &[FileChange]) -> Result<()> {
+    let mut transaction = Transaction::begin(dst)?;
+
+    // NOTE staging failures (eg. full disk) happen before `dst` is touched
+    for change in changes {
+        transaction.stage(change)?;
+    }
+
+    for change in changes {
+        if let Err(err) = transaction.commit(change) {
+            log::error!("Failed to apply changes to {:?}: {}", dst, err);
+            if let Err(rollback_err) = transaction.rollback() {
+                return Err(rollback_err.wrap_err(format!(
+                    "Rollback after a failed apply ({}) failed, {:?} might be left in an inconsistent state",
+                    err, dst
+                )));
+            }
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Renders the `changes` of `dst` as a git-format unified diff that can be
+/// applied using `git apply`.
+pub fn format_patch(dst: &Path, changes: &[FileChange]) -> Result<String> {
+    let mut patch = String::new();
+
+    for change in changes {
+        let path = change.path.display();
+        let target_path = dst.join(&change.path);
+
+        // NOTE extended header lines, ie. creation, removal and mode changes
+        let mut extended = String::new();
+        let old = match change.kind {
+            ChangeKind::Create => {
+                extended.push_str(&format!("new file mode {}\n", git_mode(change)));
+                Vec::new()
+            }
+            ChangeKind::Modify => {
+                let (old_mode, new_mode) = (git_mode_of(&target_path)?, git_mode(change));
+                if old_mode != new_mode {
+                    extended.push_str(&format!("old mode {}\nnew mode {}\n", old_mode, new_mode));
+                }
+                read_entry(&target_path)?
+            }
+            ChangeKind::Delete => {
+                extended.push_str(&format!(
+                    "deleted file mode {}\n",
+                    git_mode_of(&target_path)?
+                ));
+                read_entry(&target_path)?
+            }
+        };
+
+        let hunks = match (
+            std::str::from_utf8(&old),
+            std::str::from_utf8(&change.contents),
+        ) {
+            _ if old == change.contents => String::new(),
+            (Ok(old), Ok(new)) => unified_diff(old, new, 3),
+            _ => Err(PatchError::BinaryFileError(change.path.clone()))?,
+        };
+        if hunks.is_empty() && extended.is_empty() {
+            continue;
+        }
+
+        patch.push_str(&format!("diff --git a/{} b/{}\n", path, path));
+        patch.push_str(&extended);
+        if hunks.is_empty() {
+            continue;
+        }
+
+        match change.kind {
+            ChangeKind::Create => patch.push_str("--- /dev/null\n"),
+            ChangeKind::Modify | ChangeKind::Delete => patch.push_str(&format!("--- a/{}\n", path)),
+        }
+        match change.kind {
+            ChangeKind::Delete => patch.push_str("+++ /dev/null\n"),
+            _ => patch.push_str(&format!("+++ b/{}\n", path)),
+        }
+        patch.push_str(&hunks);
+    }
+
+    Ok(patch)
+}
+
+/// Git mode of the entry written by `change`.
+fn git_mode(change: &FileChange) -> u32 {
+    match (&change.symlink, change.mode & 0o111) {
+        (Some(_), _) => 120000,
+        (None, 0) => 100644,
+        (None, _) => 100755,
+    }
+}
+
+/// Git mode of an entry, git only distinguishes symlinks, executable and
+/// regular files.
+fn git_mode_of(path: &Path) -> Result<u32> {
+    let metadata = path.symlink_metadata()?;
+
+    Ok(match metadata.permissions().mode() & 0o111 {
+        _ if metadata.file_type().is_symlink() => 120000,
+        0 => 100644,
+        _ => 100755,
+    })
+}
+
+/// Formats the differences of all files in `old_dir` against their
+/// counterparts in `new_dir` as a git style patch. Files present only in
+/// `new_dir` aren't included.
+pub fn format_diff(old_dir: &Path, new_dir: &Path) -> Result<String> {
+    let mut patch = String::new();
+
+    for entry in WalkDir::new(old_dir).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+
+        let relative_path = entry.path().strip_prefix(old_dir)?;
+        let path = relative_path.display();
+        let new_path = new_dir.join(relative_path);
+        let old_mode = git_mode_of(entry.path())?;
+
+        let old = read_entry(entry.path())?;
+        let (new, header) = if new_path.symlink_metadata().is_ok() {
+            let new_mode = git_mode_of(&new_path)?;
+            let header = if old_mode != new_mode {
+                format!("old mode {}\nnew mode {}\n", old_mode, new_mode)
+            } else {
+                String::new()
+            };
+            (Some(read_entry(&new_path)?), header)
+        } else {
+            (None, format!("deleted file mode {}\n", old_mode))
+        };
+
+        if new.as_ref() == Some(&old) && header.is_empty() {
+            continue;
+        }
+
+        patch.push_str(&format!("diff --git a/{} b/{}\n", path, path));
+        patch.push_str(&header);
+
+        let (Ok(old), Ok(new)) = (
+            std::str::from_utf8(&old),
+            std::str::from_utf8(new.as_deref().unwrap_or_default()),
+        ) else {
+            patch.push_str(&format!("Binary files a/{} and b/{} differ\n", path, path));
+            continue;
+        };
+
+        let hunks = unified_diff(old, new, 3);
+        if hunks.is_empty() {
+            continue;
+        }
+
+        patch.push_str(&format!("--- a/{}\n", path));
+        if new_path.symlink_metadata().is_ok() {
+            patch.push_str(&format!("+++ b/{}\n", path));
+        } else {
+            patch.push_str("+++ /dev/null\n");
+        }
+        patch.push_str(&hunks);
+    }
+
+    Ok(patch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_utils::reset_permissions;
+
+    #[test]
+    fn test_plan_changes() -> Result<()> {
+        let src = TempDir::new()?;
+        let dst = TempDir::new()?;
+        fs::create_dir_all(src.path().join("nested"))?;
+        fs::write(src.path().join("nested").join("new.txt"), "new")?;
+        fs::write(src.path().join("same.txt"), "same")?;
+        fs::write(src.path().join("different.txt"), "theirs")?;
+        fs::write(dst.path().join("same.txt"), "same")?;
+        fs::write(dst.path().join("different.txt"), "ours")?;
+
+        let mut resolved = Vec::new();
+        let changes = plan_changes(
+            src.path(),
+            dst.path(),
+            &BTreeMap::new(),
+            |path, ours, theirs| {
+                resolved.push((path.to_path_buf(), ours.to_vec(), theirs.to_vec()));
+                Ok(Some(b"resolved".to_vec()))
+            },
+        )?;
+
+        assert_eq!(
+            resolved,
+            vec![(
+                PathBuf::from("different.txt"),
+                b"ours".to_vec(),
+                b"theirs".to_vec()
+            )]
+        );
+        assert_eq!(
+            changes,
+            vec![
+                FileChange::new(
+                    PathBuf::from("different.txt"),
+                    ChangeKind::Modify,
+                    b"resolved".to_vec()
+                ),
+                FileChange::new(
+                    PathBuf::from("nested/new.txt"),
+                    ChangeKind::Create,
+                    b"new".to_vec()
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_changes_skipped() -> Result<()> {
+        let src = TempDir::new()?;
+        let dst = TempDir::new()?;
+        fs::write(src.path().join("file.txt"), "theirs")?;
+        fs::write(dst.path().join("file.txt"), "ours")?;
+
+        let changes = plan_changes(src.path(), dst.path(), &BTreeMap::new(), |_, _, _| Ok(None))?;
+        assert!(changes.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_changes() -> Result<()> {
+        let dst = TempDir::new()?;
+        fs::write(dst.path().join("existing.txt"), "old")?;
+
+        apply_changes(
+            dst.path(),
+            &[
+                FileChange::new(
+                    PathBuf::from("existing.txt"),
+                    ChangeKind::Modify,
+                    b"modified".to_vec(),
+                ),
+                FileChange::new(
+                    PathBuf::from("a/b/new.txt"),
+                    ChangeKind::Create,
+                    b"new".to_vec(),
+                ),
+            ],
+        )?;
+
+        assert_eq!(
+            fs::read_to_string(dst.path().join("existing.txt"))?,
+            "modified"
+        );
+        assert_eq!(
+            fs::read_to_string(dst.path().join("a").join("b").join("new.txt"))?,
+            "new"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_changes_rollback() -> Result<()> {
+        let dst = TempDir::new()?;
+        fs::write(dst.path().join("existing.txt"), "old")?;
+        fs::write(dst.path().join("removed.txt"), "removed")?;
+        fs::write(dst.path().join("blocker"), "not a directory")?;
+
+        let result = apply_changes(
+            dst.path(),
+            &[
+                FileChange::new(
+                    PathBuf::from("existing.txt"),
+                    ChangeKind::Modify,
+                    b"modified".to_vec(),
+                ),
+                FileChange::new(
+                    PathBuf::from("a/b/new.txt"),
+                    ChangeKind::Create,
+                    b"new".to_vec(),
+                ),
+                FileChange::new(PathBuf::from("removed.txt"), ChangeKind::Delete, Vec::new()),
+                // NOTE fails, since `blocker` is a file
+                FileChange::new(
+                    PathBuf::from("blocker/file.txt"),
+                    ChangeKind::Create,
+                    b"fail".to_vec(),
+                ),
+            ],
+        );
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(dst.path().join("existing.txt"))?, "old");
+        assert!(!dst.path().join("a").exists());
+        assert_eq!(
+            fs::read_to_string(dst.path().join("removed.txt"))?,
+            "removed"
+        );
+
+        let mut entries = fs::read_dir(dst.path())?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort();
+        assert_eq!(entries, vec!["blocker", "existing.txt", "removed.txt"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_patch() -> Result<()> {
+        let dst = TempDir::new()?;
+        fs::write(dst.path().join("existing.txt"), "a\nb\n")?;
+
+        let patch = format_patch(
+            dst.path(),
+            &[
+                FileChange::new(
+                    PathBuf::from("existing.txt"),
+                    ChangeKind::Modify,
+                    b"a\nc\n".to_vec(),
+                ),
+                FileChange::new(
+                    PathBuf::from("nested/new.txt"),
+                    ChangeKind::Create,
+                    b"new\n".to_vec(),
+                ),
+            ],
+        )?;
+
+        assert_eq!(
+            patch,
+            "diff --git a/existing.txt b/existing.txt\n\
+             --- a/existing.txt\n\
+             +++ b/existing.txt\n\
+             @@ -1,2 +1,2 @@\n a\n-b\n+c\n\
+             diff --git a/nested/new.txt b/nested/new.txt\n\
+             new file mode 100644\n\
+             --- /dev/null\n\
+             +++ b/nested/new.txt\n\
+             @@ -0,0 +1 @@\n+new\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_patch_modes() -> Result<()> {
+        let dst = TempDir::new()?;
+        fs::write(dst.path().join("script.sh"), "echo\n")?;
+        fs::set_permissions(
+            dst.path().join("script.sh"),
+            fs::Permissions::from_mode(0o644),
+        )?;
+        fs::write(dst.path().join("unchanged.txt"), "a\n")?;
+        fs::set_permissions(
+            dst.path().join("unchanged.txt"),
+            fs::Permissions::from_mode(0o644),
+        )?;
+
+        let patch = format_patch(
+            dst.path(),
+            &[
+                FileChange::new(
+                    PathBuf::from("script.sh"),
+                    ChangeKind::Modify,
+                    b"echo\n".to_vec(),
+                )
+                .with_mode(0o755),
+                FileChange::new(
+                    PathBuf::from("unchanged.txt"),
+                    ChangeKind::Modify,
+                    b"a\n".to_vec(),
+                )
+                .with_mode(0o600),
+            ],
+        )?;
+
+        assert_eq!(
+            patch,
+            "diff --git a/script.sh b/script.sh\n\
+             old mode 100644\n\
+             new mode 100755\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_patch_binary() -> Result<()> {
+        let dst = TempDir::new()?;
+        fs::write(dst.path().join("image.png"), [0xff, 0x00])?;
+
+        let result = format_patch(
+            dst.path(),
+            &[FileChange::new(
+                PathBuf::from("image.png"),
+                ChangeKind::Modify,
+                vec![0xff, 0x01],
+            )],
+        );
+
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_diff() -> Result<()> {
+        let old = TempDir::new()?;
+        let new = TempDir::new()?;
+        fs::write(old.path().join("changed.txt"), "a\nb\n")?;
+        fs::write(old.path().join("deleted.txt"), "gone\n")?;
+        fs::write(old.path().join("same.txt"), "same\n")?;
+        fs::write(new.path().join("changed.txt"), "a\nc\n")?;
+        fs::write(new.path().join("same.txt"), "same\n")?;
+        fs::write(new.path().join("untracked.txt"), "ignored\n")?;
+        reset_permissions(old.path().to_str().unwrap())?;
+        reset_permissions(new.path().to_str().unwrap())?;
+
+        assert_eq!(
+            format_diff(old.path(), new.path())?,
+            "diff --git a/changed.txt b/changed.txt\n\
+             --- a/changed.txt\n\
+             +++ b/changed.txt\n\
+             @@ -1,2 +1,2 @@\n a\n-b\n+c\n\
+             diff --git a/deleted.txt b/deleted.txt\n\
+             deleted file mode 100644\n\
+             --- a/deleted.txt\n\
+             +++ /dev/null\n\
+             @@ -1 +0,0 @@\n-gone\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_changes_modes() -> Result<()> {
+        let src = TempDir::new()?;
+        let dst = TempDir::new()?;
+        fs::write(src.path().join("script.sh"), "#!/bin/sh")?;
+        fs::set_permissions(
+            src.path().join("script.sh"),
+            fs::Permissions::from_mode(0o755),
+        )?;
+        fs::write(src.path().join("secret"), "new")?;
+        fs::write(dst.path().join("secret"), "old")?;
+        fs::set_permissions(dst.path().join("secret"), fs::Permissions::from_mode(0o600))?;
+
+        let changes = plan_changes(src.path(), dst.path(), &BTreeMap::new(), |_, _, theirs| {
+            Ok(Some(theirs.to_vec()))
+        })?;
+        apply_changes(dst.path(), &changes)?;
+
+        assert_eq!(mode_of(&dst.path().join("script.sh"))?, 0o755);
+        assert_eq!(mode_of(&dst.path().join("secret"))?, 0o600);
+        assert_eq!(fs::read_to_string(dst.path().join("secret"))?, "new");
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_changes_declared_modes() -> Result<()> {
+        let src = TempDir::new()?;
+        let dst = TempDir::new()?;
+        for (file, contents) in [
+            ("key", "same"),
+            ("public", "same"),
+            ("secret", "new"),
+            ("other", "new"),
+        ] {
+            fs::write(src.path().join(file), contents)?;
+        }
+        for (file, contents) in [
+            ("key", "same"),
+            ("public", "same"),
+            ("secret", "old"),
+            ("other", "old"),
+        ] {
+            fs::write(dst.path().join(file), contents)?;
+            fs::set_permissions(dst.path().join(file), fs::Permissions::from_mode(0o644))?;
+        }
+
+        let declared_modes = BTreeMap::from([
+            (PathBuf::from("key"), 0o600),
+            (PathBuf::from("public"), 0o644),
+            (PathBuf::from("secret"), 0o600),
+        ]);
+        let changes = plan_changes(src.path(), dst.path(), &declared_modes, |_, _, theirs| {
+            Ok(Some(theirs.to_vec()))
+        })?;
+
+        // NOTE the unchanged file with a different declared mode is changed
+        // only by its mode, the one with the same mode isn't changed at all
+        assert_eq!(changes.len(), 3);
+        let key = changes
+            .iter()
+            .find(|change| change.path == Path::new("key"))
+            .unwrap();
+        assert_eq!(key.kind, ChangeKind::Modify);
+        assert_eq!(key.contents, b"same");
+        assert_eq!(key.mode, 0o600);
+        assert!(!changes
+            .iter()
+            .any(|change| change.path == Path::new("public")));
+
+        apply_changes(dst.path(), &changes)?;
+        assert_eq!(mode_of(&dst.path().join("key"))?, 0o600);
+        assert_eq!(mode_of(&dst.path().join("secret"))?, 0o600);
+        assert_eq!(mode_of(&dst.path().join("other"))?, 0o644);
+        assert_eq!(fs::read_to_string(dst.path().join("secret"))?, "new");
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! Packaging of a prepared project directory into an archive instead of
+//! copying it to a target directory.
+use color_eyre::eyre::Result;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs;
+use std::io::{Cursor, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use walkdir::WalkDir;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+#[derive(Error, Debug)]
+pub enum ArchiveError {
+    #[error("Unsupported archive format of {0:?}, please use one of `.tar`, `.tar.gz`, `.tgz` or `.zip`")]
+    UnknownFormatError(PathBuf),
+}
+
+impl ArchiveFormat {
+    /// Infers the archive format from the extension of `path`, writing
+    /// to stdout (`-`) always uses `.tar.gz`.
+    pub fn from_path(path: &Path) -> Result<Self, ArchiveError> {
+        let file_name = path.to_string_lossy();
+
+        match file_name.as_ref() {
+            "-" => Ok(Self::TarGz),
+            name if name.ends_with(".tar") => Ok(Self::Tar),
+            name if name.ends_with(".tar.gz") || name.ends_with(".tgz") => Ok(Self::TarGz),
+            name if name.ends_with(".zip") => Ok(Self::Zip),
+            _ => Err(ArchiveError::UnknownFormatError(path.to_path_buf())),
+        }
+    }
+}
+
+fn write_tar<W: Write>(src: &Path, prefix: &str, writer: W) -> Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    builder.append_dir_all(prefix, src)?;
+    Ok(builder.into_inner()?)
+}
+
+fn write_zip<W: Write>(src: &Path, prefix: &str, mut writer: W) -> Result<W> {
+    // NOTE zip archives need a seekable writer, which stdout isn't
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+
+    for entry in WalkDir::new(src).sort_by_file_name() {
+        let entry = entry?;
+        let relative_path = entry.path().strip_prefix(src)?;
+        let name = Path::new(prefix).join(relative_path);
+        let name = name.to_string_lossy();
+
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(entry.path().symlink_metadata()?.permissions().mode());
+
+        if entry.path_is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            zip.add_symlink(name, target.to_string_lossy(), options)?;
+        } else if entry.file_type().is_dir() {
+            zip.add_directory(name, options)?;
+        } else {
+            zip.start_file(name, options)?;
+            zip.write_all(&fs::read(entry.path())?)?;
+        }
+    }
+
+    writer.write_all(&zip.finish()?.into_inner())?;
+    Ok(writer)
+}
+
+/// Packages all of the files in `src` into an archive written to `writer`,
+/// every entry is placed inside of the `prefix` directory.
+pub fn write_archive<W: Write>(
+    src: &Path,
+    prefix: &str,
+    format: ArchiveFormat,
+    writer: W,
+) -> Result<W> {
+    log::info!("Packaging {:?} into a {:?} archive", src, format);
+
+    match format {
+        ArchiveFormat::Tar => write_tar(src, prefix, writer),
+        ArchiveFormat::TarGz => {
+            let encoder = write_tar(src, prefix, GzEncoder::new(writer, Compression::default()))?;
+            Ok(encoder.finish()?)
+        }
+        ArchiveFormat::Zip => write_zip(src, prefix, writer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_utils::reset_permissions;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    fn prepare_project() -> Result<TempDir> {
+        let src = TempDir::new()?;
+        fs::create_dir_all(src.path().join("flake-parts"))?;
+        fs::write(src.path().join("flake.nix"), "{ }")?;
+        fs::write(src.path().join("flake-parts").join("part.nix"), "{ }")?;
+        fs::write(src.path().join("script.sh"), "#!/bin/sh")?;
+        reset_permissions(src.path().to_str().unwrap())?;
+        fs::set_permissions(
+            src.path().join("script.sh"),
+            fs::Permissions::from_mode(0o755),
+        )?;
+        Ok(src)
+    }
+
+    #[test]
+    fn test_archive_format_from_path() {
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("project.tar")).unwrap(),
+            ArchiveFormat::Tar
+        );
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("project.tgz")).unwrap(),
+            ArchiveFormat::TarGz
+        );
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("-")).unwrap(),
+            ArchiveFormat::TarGz
+        );
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("project.zip")).unwrap(),
+            ArchiveFormat::Zip
+        );
+        assert!(ArchiveFormat::from_path(Path::new("project.rar")).is_err());
+    }
+
+    #[test]
+    fn test_write_tar_gz_archive() -> Result<()> {
+        let src = prepare_project()?;
+        let compressed = write_archive(src.path(), "project", ArchiveFormat::TarGz, Vec::new())?;
+
+        let mut archive = tar::Archive::new(GzDecoder::new(compressed.as_slice()));
+        let mut entries = Vec::new();
+        for entry in archive.entries()? {
+            let entry = entry?;
+            entries.push((
+                entry.path()?.to_string_lossy().to_string(),
+                entry.header().mode()? & 0o777,
+            ));
+        }
+        entries.sort();
+
+        assert_eq!(
+            entries,
+            vec![
+                ("project/".to_string(), 0o755),
+                ("project/flake-parts".to_string(), 0o755),
+                ("project/flake-parts/part.nix".to_string(), 0o644),
+                ("project/flake.nix".to_string(), 0o644),
+                ("project/script.sh".to_string(), 0o755),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_zip_archive() -> Result<()> {
+        let src = prepare_project()?;
+        let compressed = write_archive(src.path(), "project", ArchiveFormat::Zip, Vec::new())?;
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(compressed))?;
+        let mut script = archive.by_name("project/script.sh")?;
+        assert_eq!(script.unix_mode().map(|mode| mode & 0o777), Some(0o755));
+
+        let mut contents = String::new();
+        script.read_to_string(&mut contents)?;
+        assert_eq!(contents, "#!/bin/sh");
+        Ok(())
+    }
+}
@@ -1,12 +1,18 @@
 use clap::Args;
 use color_eyre::eyre::Result;
-use fs_extra::dir::{self, CopyOptions};
 use tempfile::tempdir;
 
-use crate::cmd::init::{parse_required_parts_tuples, prepare_tmpdir, InitCommand};
+use crate::cmd::init::{
+    declared_modes, ensure_clean_target, flake_inputs_change, parse_required_parts_tuples,
+    plan_target_changes, prepare_tmpdir, run_target_hooks, track_target_changes, update_lock,
+    write_patch, InitCommand,
+};
 use crate::config::{BASE_DERIVATION_NAME, SELF_FLAKE_URI};
+use crate::history::{apply_recorded, current_command};
+use crate::lock::ProjectLock;
 use crate::nix::NixCmdInterface;
 use crate::parts::FlakePartsStore;
+use crate::project::find_project_root;
 use crate::templates::FlakeInputsContext;
 
 //    TODO: I might figure out a way to do this automatically in the future, but
@@ -20,14 +26,48 @@ use crate::templates::FlakeInputsContext;
 ///
 /// 2. `flake.nix` is left untouched as the user may have already made manual changes.
 ///    Additional inputs will be printed to the console and the user is
-///    advised to add them manually.
+///    advised to add them manually. When using `--emit-patch`, the missing
+///    inputs are added to `flake.nix` in the patch instead.
+///
+/// The project root is discovered by walking upward from the provided path
+/// (the current directory by default).
 #[derive(Debug, Args)]
+#[command(mut_arg("path", |arg| arg
+    .required(false)
+    .default_value(".")
+    .help("Path (relative or absolute) inside of an already initialized flake-parts project, its root is discovered automatically")
+))]
+#[command(mut_arg("existing_flake", |arg| arg.hide(true)))]
+#[command(mut_arg("archive", |arg| arg.hide(true)))]
+#[command(mut_arg("from", |arg| arg.hide(true)))]
+#[command(mut_arg("nixpkgs", |arg| arg.hide(true).env(None)))]
 pub struct AddCommand {
     #[clap(flatten)]
     pub init: InitCommand,
 }
 
 pub fn add(mut cmd: AddCommand, nix_cmd: impl NixCmdInterface) -> Result<()> {
+    // NOTE these flags only apply to new projects, they're rejected before
+    // any of the stores is fetched
+    let init_only_flags = [
+        ("--archive", cmd.init.archive.is_some()),
+        ("--from", cmd.init.from.is_some()),
+        ("--nixpkgs", cmd.init.nixpkgs.is_some()),
+        ("--existing-flake", cmd.init.existing_flake.is_some()),
+    ];
+    for (flag, _) in init_only_flags.iter().filter(|(_, passed)| *passed) {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!(
+                "The `{}` flag is only supported by the `init` command",
+                flag
+            ),
+        ))?
+    }
+
+    // NOTE only the stores passed by the user are recorded in the lock
+    let user_stores = cmd.init.shared_args.parts_stores.clone();
+
     if !cmd.init.shared_args.disable_base_parts {
         log::info!("Adding base parts store to `cmd.shared_args.parts_stores`");
 
@@ -54,32 +94,46 @@ pub fn add(mut cmd: AddCommand, nix_cmd: impl NixCmdInterface) -> Result<()> {
             .collect::<Vec<_>>()
     );
 
+    cmd.init.expand_presets(&stores)?;
     let parts_tuples = parse_required_parts_tuples(&cmd.init, &stores)?;
 
-    let path = cmd
+    let user_path = cmd
         .init
         .path
         .canonicalize()
         .unwrap_or_else(|_| cmd.init.path.clone());
 
-    log::debug!("Full user provided path: {:?}", path);
+    log::debug!("Full user provided path: {:?}", user_path);
 
-    if !path.exists() {
+    if !user_path.exists() {
         Err(std::io::Error::new(
             std::io::ErrorKind::NotFound,
-            format!("Path {:?} does not exist", path),
+            format!("Path {:?} does not exist", user_path),
         ))?
     }
 
+    let path = find_project_root(&user_path)?;
+    if path != user_path {
+        log::info!("Using the project root at {:?}", path);
+    }
+
+    let mut lock = ProjectLock::read(&path)?.unwrap_or_default();
+    // NOTE files excluded by previous operations are never reintroduced
+    lock.extend_exclude_files(&cmd.init.exclude_files);
+
+    let target_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
     let tmpdir = tempdir()?;
     log::info!("Preparing new additions in a tmpdir at {:?}", tmpdir.path());
     prepare_tmpdir(
         &nix_cmd,
         &tmpdir,
         &parts_tuples,
-        path.file_name().map(|osstr| osstr.to_str().unwrap()),
-        &cmd.init.strategy,
-        false,
+        target_name.as_deref(),
+        &cmd.init.strategy(),
+        &lock.exclude_patterns()?,
+        None,
     )?;
 
     // NOTE the flake.nix file shouldn't be present due to the strucutre of
@@ -98,18 +152,35 @@ pub fn add(mut cmd: AddCommand, nix_cmd: impl NixCmdInterface) -> Result<()> {
     let flake_context = FlakeInputsContext::from_merged_metadata(&metadata);
 
     let rendered = flake_context.render()?;
+
+    log::info!("Addition succesfully prepared in tmpdir, now copying to target directory");
+    let mut changes =
+        plan_target_changes(&cmd.init, &tmpdir, &path, &declared_modes(&parts_tuples))?;
+
+    update_lock(
+        &mut lock,
+        &user_stores,
+        &parts_tuples,
+        cmd.init.shared_args.disable_base_parts,
+    );
+    if let Some(change) = lock.change(&path)? {
+        changes.push(change);
+    }
+
+    if let Some(patch_file) = &cmd.init.emit_patch {
+        if let Some(change) = flake_inputs_change(&path, &flake_context)? {
+            changes.push(change);
+        }
+        return write_patch(patch_file, &path, &changes);
+    }
+
     println!("Please add the following snippet to your `flake.nix` inputs:");
     println!("{}", rendered);
 
-    log::info!("Addition succesfully prepared in tmpdir, now copying to target directory");
-    dir::copy(
-        &tmpdir,
-        &cmd.init.path,
-        &CopyOptions::new()
-            .content_only(true)
-            .skip_exist(!cmd.init.force)
-            .overwrite(cmd.init.force),
-    )?;
+    ensure_clean_target(&cmd.init, &path, &changes)?;
+    apply_recorded(&path, &current_command(), &changes)?;
+    track_target_changes(&cmd.init, &path, &changes, &parts_tuples)?;
+    run_target_hooks(&cmd.init, &path, &parts_tuples)?;
 
     Ok(())
 }
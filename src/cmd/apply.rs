@@ -0,0 +1,232 @@
+use clap::Args;
+use color_eyre::eyre::Result;
+use std::path::PathBuf;
+
+use crate::cmd::add::{add, AddCommand};
+use crate::cmd::init::{init, parse_required_parts_tuples, InitCommand};
+use crate::cmd::SharedArgs;
+use crate::config::{BASE_DERIVATION_NAME, BOOTSTRAP_DERIVATION_NAME, SELF_FLAKE_URI};
+use crate::doctor::installed_parts;
+use crate::lock::ProjectLock;
+use crate::manifest::{Manifest, ManifestError};
+use crate::nix::NixCmdInterface;
+use crate::parts::{FlakePartTuple, FlakePartsStore};
+use crate::project::is_project_root;
+use crate::user_config::UserConfig;
+
+/// Reconcile the project with its `flake-parts.toml` (or `flake-parts.nix`)
+/// manifest.
+///
+/// The manifest declares the stores, parts, merge strategy and the rest of
+/// the `init` options, for example
+///
+///   stores = ["github:org/parts#flake-parts"]
+///   parts = ["treefmt", "shells"]
+///   strategy = "merge"
+///   existing-flake = "add"
+///   nixpkgs = "github:nixos/nixpkgs/nixos-24.05"
+///   exclude-files = ["README.md"]
+///
+/// If the directory isn't a flake-parts project yet, it's initialized with
+/// all of the declared parts. Otherwise only the missing parts (including
+/// their dependencies) are added and installed parts that the manifest
+/// doesn't declare are reported.
+#[derive(Debug, Args)]
+#[command(verbatim_doc_comment)]
+pub struct ApplyCommand {
+    #[clap(flatten)]
+    pub shared_args: SharedArgs,
+
+    /// Path (relative or absolute) of the project
+    #[arg(default_value = ".")]
+    pub path: PathBuf,
+
+    /// Path to the manifest, defaults to `flake-parts.toml` or
+    /// `flake-parts.nix` in the project
+    #[arg(short, long = "manifest", value_name = "FILE", verbatim_doc_comment)]
+    pub manifest: Option<PathBuf>,
+
+    /// Allow modifying files with uncommitted changes in case the project
+    /// is inside of a git repository.
+    #[arg(long = "allow-dirty", default_value_t = false, verbatim_doc_comment)]
+    pub allow_dirty: bool,
+
+    /// Stage all generated files in git, see `init --help`
+    #[arg(long = "git", default_value_t = false)]
+    pub git: bool,
+
+    /// Run the post-apply hooks declared by the parts without asking
+    #[arg(long = "run-hooks", default_value_t = false)]
+    pub run_hooks: bool,
+
+    /// Never run the post-apply hooks declared by the parts
+    #[arg(
+        long = "no-hooks",
+        default_value_t = false,
+        conflicts_with = "run_hooks"
+    )]
+    pub no_hooks: bool,
+
+    #[arg(skip)]
+    pub user_config: UserConfig,
+}
+
+impl ApplyCommand {
+    /// Builds the `init` command adding the `parts` according to the
+    /// `manifest`, options passed on the command line take precedence. The
+    /// user configuration fills in the options the manifest doesn't set.
+    fn init_command(
+        &self,
+        manifest: &Manifest,
+        path: PathBuf,
+        parts: Vec<String>,
+    ) -> Result<InitCommand> {
+        let mut parts_stores = self.shared_args.parts_stores.clone();
+        for store in &manifest.stores {
+            if !parts_stores.contains(store) {
+                parts_stores.push(store.clone());
+            }
+        }
+
+        // NOTE the manifest takes precedence over the user configuration, so
+        // its value is passed on as an explicit choice
+        let disable_base = self.shared_args.disable_base_choice()?;
+        let mut init_cmd = InitCommand {
+            shared_args: SharedArgs {
+                parts_stores,
+                disable_base_parts: disable_base.unwrap_or(manifest.disable_base),
+                enable_base_parts: disable_base == Some(false),
+                verbose: self.shared_args.verbose,
+            },
+            path,
+            parts,
+            strategy: manifest.strategy,
+            ignore_conflicts: manifest.ignore_conflicts,
+            ignore_unresolved_deps: manifest.ignore_unresolved_deps,
+            force: false,
+            interactive: false,
+            allow_dirty: self.allow_dirty,
+            git: self.git,
+            intent_to_add: false,
+            commit: None,
+            emit_patch: None,
+            archive: None,
+            run_hooks: self.run_hooks,
+            no_hooks: self.no_hooks,
+            existing_flake: manifest.existing_flake,
+            from: None,
+            nixpkgs: manifest.nixpkgs.clone(),
+            exclude_files: manifest.exclude_files.clone(),
+            presets: manifest.presets.clone(),
+        };
+        self.user_config.apply_to_init(&mut init_cmd)?;
+        Ok(init_cmd)
+    }
+}
+
+pub fn apply(cmd: ApplyCommand, nix_cmd: impl NixCmdInterface) -> Result<()> {
+    let path = cmd.path.canonicalize()?;
+    log::debug!("Full user provided path: {:?}", path);
+
+    let manifest_path = match &cmd.manifest {
+        Some(manifest_path) => manifest_path.clone(),
+        None => Manifest::find(&path).ok_or(ManifestError::NoManifestError(path.clone()))?,
+    };
+    log::info!("Reading the manifest {:?}", manifest_path);
+    let manifest = Manifest::from_file(&manifest_path, &nix_cmd)?;
+    log::debug!("Manifest: {:?}", manifest);
+
+    if !is_project_root(&path) {
+        log::info!(
+            "{:?} isn't a flake-parts project yet, initializing it",
+            path
+        );
+        let init_cmd = cmd.init_command(&manifest, path, manifest.parts.clone())?;
+        return init(init_cmd, nix_cmd);
+    }
+
+    let mut init_cmd = cmd.init_command(&manifest, path.clone(), manifest.parts.clone())?;
+
+    let mut parts_stores = init_cmd.shared_args.parts_stores.clone();
+    if !init_cmd.shared_args.disable_base_parts {
+        parts_stores.push(format!("{}#{}", SELF_FLAKE_URI, BASE_DERIVATION_NAME));
+    }
+    parts_stores.push(format!("{}#{}", SELF_FLAKE_URI, BOOTSTRAP_DERIVATION_NAME));
+
+    let stores = parts_stores
+        .iter()
+        .map(|store| FlakePartsStore::from_flake_uri(store, &nix_cmd))
+        .collect::<Result<Vec<_>>>()?;
+
+    init_cmd.expand_presets(&stores)?;
+    let declared_uris = parse_required_parts_tuples(&init_cmd, &stores)?
+        .iter()
+        .map(|part_tuple| part_tuple.to_flake_uri(None))
+        .collect::<Vec<_>>();
+
+    let parts_tuples = stores
+        .iter()
+        .flat_map(|store| {
+            store
+                .parts
+                .iter()
+                .map(move |part| FlakePartTuple::new(store, part.to_owned()))
+        })
+        .collect::<Vec<_>>();
+    let bootstrap_uris = parts_tuples
+        .iter()
+        .filter(|part_tuple| part_tuple.part.name == "_bootstrap")
+        .map(|part_tuple| part_tuple.to_flake_uri(None))
+        .collect::<Vec<_>>();
+
+    // NOTE the lock also records the parts of stores the manifest doesn't
+    // declare anymore, so these are reported as well
+    let mut installed_uris = match ProjectLock::read(&path)? {
+        Some(lock) => lock.parts,
+        None => installed_parts(&path, parts_tuples)?
+            .iter()
+            .map(|part_tuple| part_tuple.to_flake_uri(None))
+            .collect(),
+    };
+    installed_uris.retain(|uri| !bootstrap_uris.contains(uri));
+
+    let extra_parts = installed_uris
+        .iter()
+        .filter(|uri| !declared_uris.contains(uri))
+        .collect::<Vec<_>>();
+
+    if !extra_parts.is_empty() {
+        println!("The following installed parts aren't declared in the manifest:");
+        for part in &extra_parts {
+            println!("  - {}", part);
+        }
+    }
+
+    let missing_parts = declared_uris
+        .into_iter()
+        .filter(|uri| !installed_uris.contains(uri))
+        .collect::<Vec<_>>();
+
+    if missing_parts.is_empty() {
+        println!("The project is up to date with the manifest");
+        return Ok(());
+    }
+
+    log::info!("Adding missing parts: {:?}", missing_parts);
+
+    // NOTE the exclusions are kept so that they also apply to the
+    // dependencies resolved by `add`
+    let exclusions = init_cmd
+        .parts
+        .iter()
+        .filter(|part| part.starts_with('!'))
+        .cloned()
+        .collect::<Vec<_>>();
+    init_cmd.parts = missing_parts;
+    init_cmd.parts.extend(exclusions);
+    // NOTE these only apply to new projects and are rejected by `add`
+    init_cmd.nixpkgs = None;
+    init_cmd.existing_flake = None;
+
+    add(AddCommand { init: init_cmd }, nix_cmd)
+}
@@ -0,0 +1,130 @@
+use clap::Args;
+use color_eyre::eyre::Result;
+use std::path::PathBuf;
+use tempfile::tempdir;
+use thiserror::Error;
+
+use crate::apply::format_diff;
+use crate::cmd::init::{prepare_tmpdir, InitStrategy};
+use crate::cmd::SharedArgs;
+use crate::config::{BASE_DERIVATION_NAME, BOOTSTRAP_DERIVATION_NAME, SELF_FLAKE_URI};
+use crate::doctor::installed_parts;
+use crate::lock::ProjectLock;
+use crate::nix::NixCmdInterface;
+use crate::parts::{FlakePartTuple, FlakePartsStore};
+use crate::project::find_project_root;
+
+/// Show how the project files differ from the pristine versions of
+/// their parts.
+///
+/// The current version of each part is fetched from its store, prepared
+/// the same way `init` does it (including the NAMEPLACEHOLDER
+/// substitution) and compared to the files in the project. The output
+/// is a unified diff from the part to the project, so it contains both
+/// the local customisations and the upstream changes since the part
+/// was added.
+#[derive(Debug, Args)]
+#[command(verbatim_doc_comment)]
+pub struct DiffCommand {
+    #[clap(flatten)]
+    pub shared_args: SharedArgs,
+
+    /// Part to compare, either its name or its full flake uri. If omitted,
+    /// all of the parts installed in the project (recorded in its lock)
+    /// are compared.
+    #[arg(verbatim_doc_comment)]
+    pub part: Option<String>,
+
+    /// Path (relative or absolute) inside of the project, the project root
+    /// is discovered by walking up from it
+    #[arg(short, long, default_value = ".", verbatim_doc_comment)]
+    pub path: PathBuf,
+}
+
+#[derive(Error, Debug)]
+pub enum DiffError {
+    #[error("Part {0} wasn't found in any of the parts stores. To see which ones are available use the `list` subcommand.")]
+    UnknownPartError(String),
+}
+
+pub fn diff(mut cmd: DiffCommand, nix_cmd: impl NixCmdInterface) -> Result<()> {
+    if !cmd.shared_args.disable_base_parts {
+        log::info!("Adding base parts store to `cmd.shared_args.parts_stores`");
+        cmd.shared_args
+            .parts_stores
+            .push(format!("{}#{}", SELF_FLAKE_URI, BASE_DERIVATION_NAME));
+    }
+
+    log::info!("Adding bootstrap parts store to `cmd.shared_args.parts_stores`");
+    cmd.shared_args
+        .parts_stores
+        .push(format!("{}#{}", SELF_FLAKE_URI, BOOTSTRAP_DERIVATION_NAME));
+
+    let root = find_project_root(&cmd.path.canonicalize()?)?;
+    log::debug!("Project root: {:?}", root);
+
+    let stores = cmd
+        .shared_args
+        .parts_stores
+        .iter()
+        .map(|store| FlakePartsStore::from_flake_uri(store, &nix_cmd))
+        .collect::<Result<Vec<_>>>()?;
+
+    let all_parts_tuples = stores
+        .iter()
+        .flat_map(|store| {
+            store
+                .parts
+                .iter()
+                .map(move |part| FlakePartTuple::new(store, part.to_owned()))
+        })
+        .collect::<Vec<_>>();
+
+    let exclude_files = ProjectLock::read(&root)?
+        .unwrap_or_default()
+        .exclude_patterns()?;
+
+    let parts_tuples = match &cmd.part {
+        Some(part) => {
+            let part_tuple = all_parts_tuples
+                .into_iter()
+                .find(|part_tuple| part_tuple.is_referenced_by(part))
+                .ok_or_else(|| DiffError::UnknownPartError(part.clone()))?;
+            vec![part_tuple]
+        }
+        None => installed_parts(&root, all_parts_tuples)?,
+    };
+
+    log::info!(
+        "Comparing parts {:?}",
+        parts_tuples
+            .iter()
+            .map(|part_tuple| part_tuple.to_flake_uri(None))
+            .collect::<Vec<_>>()
+    );
+
+    // NOTE all of the parts are prepared together, the same as by `init`
+    let target_name = root
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned());
+    let tmpdir = tempdir()?;
+    prepare_tmpdir(
+        &nix_cmd,
+        &tmpdir,
+        &parts_tuples,
+        target_name.as_deref(),
+        &InitStrategy::Skip,
+        &exclude_files,
+        None,
+    )?;
+
+    let patch = format_diff(tmpdir.path(), &root)?;
+
+    if patch.is_empty() {
+        println!("No differences found");
+    } else {
+        print!("{}", patch);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use clap::Args;
+use color_eyre::eyre::Result;
+use glob::Pattern;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use thiserror::Error;
+
+use crate::cmd::SharedArgs;
+use crate::config::{BASE_DERIVATION_NAME, BOOTSTRAP_DERIVATION_NAME, SELF_FLAKE_URI};
+use crate::doctor::{
+    check_inputs, declared_inputs, ignored_part_files, input_references, part_status, Finding,
+    PartStatus, Severity,
+};
+use crate::lock::ProjectLock;
+use crate::nix::NixCmdInterface;
+use crate::parts::{FlakePartTuple, FlakePartsStore};
+use crate::project::find_project_root;
+
+/// Audit an already generated project against its parts.
+///
+/// Reports inputs referenced by the project files (or required by the
+/// installed parts) that aren't declared in `flake.nix`, missing
+/// dependencies, conflicts or only partially present files of installed
+/// parts, an outdated `_bootstrap.nix` and files that `loadParts` ignores
+/// because of their `_` prefix.
+#[derive(Debug, Args)]
+#[command(verbatim_doc_comment)]
+pub struct DoctorCommand {
+    #[clap(flatten)]
+    pub shared_args: SharedArgs,
+
+    /// Path (relative or absolute) inside of the project to audit, the
+    /// project root is discovered by walking up from it
+    #[arg(default_value = ".", verbatim_doc_comment)]
+    pub path: PathBuf,
+}
+
+#[derive(Error, Debug)]
+pub enum DoctorError {
+    #[error("The project audit found {0} errors")]
+    ProblemsFoundError(usize),
+}
+
+/// Returns the findings about the parts together with the indices of the
+/// installed `parts_tuples`.
+fn check_parts(
+    root: &Path,
+    parts_tuples: &[FlakePartTuple],
+    exclude_files: &[Pattern],
+) -> Result<(Vec<Finding>, Vec<usize>)> {
+    let mut findings = Vec::new();
+    let mut installed_indices = Vec::new();
+
+    for (index, part_tuple) in parts_tuples.iter().enumerate() {
+        match part_status(root, &part_tuple.part.nix_store_path, exclude_files)? {
+            PartStatus::Absent => {}
+            PartStatus::Installed => installed_indices.push(index),
+            PartStatus::Partial { missing } => {
+                installed_indices.push(index);
+                findings.push(Finding::warning(format!(
+                    "Part {} is only partially present, missing files: {:?}",
+                    part_tuple.to_flake_uri(None),
+                    missing
+                )));
+            }
+        }
+    }
+
+    let installed_uris = installed_indices
+        .iter()
+        .map(|&index| parts_tuples[index].to_flake_uri(None))
+        .collect::<Vec<_>>();
+    log::debug!("Installed parts: {:?}", installed_uris);
+
+    for &index in &installed_indices {
+        let (resolved_deps, unresolved_deps) =
+            FlakePartTuple::resolve_dependencies_of(parts_tuples, vec![index]);
+
+        let mut missing_deps = resolved_deps
+            .into_iter()
+            .filter(|dep| !unresolved_deps.contains(dep) && !installed_uris.contains(dep))
+            .collect::<Vec<_>>();
+        missing_deps.sort();
+
+        let part_uri = parts_tuples[index].to_flake_uri(None);
+        if !missing_deps.is_empty() {
+            findings.push(Finding::error(format!(
+                "Part {} depends on {:?}, which aren't installed",
+                part_uri, missing_deps
+            )));
+        }
+        if !unresolved_deps.is_empty() {
+            findings.push(Finding::warning(format!(
+                "Dependencies {:?} of part {} couldn't be resolved, please include the necessary stores using the `-I` flag",
+                unresolved_deps, part_uri
+            )));
+        }
+    }
+
+    let installed_tuples = installed_indices
+        .iter()
+        .map(|&index| {
+            FlakePartTuple::new(parts_tuples[index].store, parts_tuples[index].part.clone())
+        })
+        .collect::<Vec<_>>();
+
+    let conflicts = FlakePartTuple::find_conflicting_parts_in(&installed_tuples);
+    if !conflicts.is_empty() {
+        findings.push(Finding::error(format!(
+            "The following installed parts conflict with each other: {:?}",
+            conflicts
+                .into_iter()
+                .map(|part_tuple| part_tuple.to_flake_uri(None))
+                .collect::<Vec<_>>()
+        )));
+    }
+
+    Ok((findings, installed_indices))
+}
+
+fn check_bootstrap(root: &Path, parts_tuples: &[FlakePartTuple]) -> Result<Vec<Finding>> {
+    let bootstrap_file = Path::new("flake-parts").join("_bootstrap.nix");
+
+    let Some(bootstrap) = parts_tuples.iter().find(|part_tuple| {
+        part_tuple.part.name == "_bootstrap"
+            && part_tuple
+                .store
+                .flake_uri
+                .ends_with(BOOTSTRAP_DERIVATION_NAME)
+    }) else {
+        log::warn!("Bootstrap part wasn't found, skipping the _bootstrap.nix check");
+        return Ok(Vec::new());
+    };
+
+    let Ok(current) = fs::read(root.join(&bootstrap_file)) else {
+        return Ok(vec![Finding::error(format!(
+            "{:?} is missing, the flake-parts directory won't be loaded",
+            bootstrap_file
+        ))]);
+    };
+
+    if current != fs::read(bootstrap.part.nix_store_path.join(&bootstrap_file))? {
+        return Ok(vec![Finding::warning(format!(
+            "{:?} differs from the version in {}, it might be outdated",
+            bootstrap_file,
+            bootstrap.to_flake_uri(None)
+        ))]);
+    }
+
+    Ok(Vec::new())
+}
+
+pub fn doctor(mut cmd: DoctorCommand, nix_cmd: impl NixCmdInterface) -> Result<()> {
+    if !cmd.shared_args.disable_base_parts {
+        log::info!("Adding base parts store to `cmd.shared_args.parts_stores`");
+        cmd.shared_args
+            .parts_stores
+            .push(format!("{}#{}", SELF_FLAKE_URI, BASE_DERIVATION_NAME));
+    }
+
+    log::info!("Adding bootstrap parts store to `cmd.shared_args.parts_stores`");
+    cmd.shared_args
+        .parts_stores
+        .push(format!("{}#{}", SELF_FLAKE_URI, BOOTSTRAP_DERIVATION_NAME));
+
+    let root = find_project_root(&cmd.path.canonicalize()?)?;
+    log::info!("Auditing the project at {:?}", root);
+
+    let stores = cmd
+        .shared_args
+        .parts_stores
+        .iter()
+        .map(|store| FlakePartsStore::from_flake_uri(store, &nix_cmd))
+        .collect::<Result<Vec<_>>>()?;
+
+    let parts_tuples = stores
+        .iter()
+        .flat_map(|store| {
+            store
+                .parts
+                .iter()
+                .map(move |part| FlakePartTuple::new(store, part.to_owned()))
+        })
+        .collect::<Vec<_>>();
+
+    let exclude_files = ProjectLock::read(&root)?
+        .unwrap_or_default()
+        .exclude_patterns()?;
+    let (mut findings, installed_indices) = check_parts(&root, &parts_tuples, &exclude_files)?;
+
+    let mut required_inputs: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for part_tuple in installed_indices.iter().map(|&index| &parts_tuples[index]) {
+        let Some(inputs) = part_tuple.part.metadata.inputs.as_object() else {
+            continue;
+        };
+        for input in inputs.keys() {
+            required_inputs
+                .entry(input.clone())
+                .or_default()
+                .insert(part_tuple.to_flake_uri(None));
+        }
+    }
+
+    let declared = declared_inputs(&fs::read_to_string(root.join("flake.nix"))?)?;
+    findings.extend(check_inputs(
+        &declared,
+        &input_references(&root)?,
+        &required_inputs,
+    ));
+
+    findings.extend(check_bootstrap(&root, &parts_tuples)?);
+
+    findings.extend(ignored_part_files(&root)?.into_iter().map(|path| {
+        Finding::warning(format!(
+            "{:?} is ignored by `loadParts` because of its prefix, rename it unless it's only imported by other files",
+            path
+        ))
+    }));
+
+    findings.sort_by_key(|finding| std::cmp::Reverse(finding.severity));
+
+    let mut stdout = StandardStream::stdout(ColorChoice::Auto);
+    for finding in &findings {
+        let (label, color) = match finding.severity {
+            Severity::Error => ("error", Color::Red),
+            Severity::Warning => ("warning", Color::Yellow),
+        };
+
+        stdout.set_color(ColorSpec::new().set_fg(Some(color)).set_bold(true))?;
+        write!(&mut stdout, "{}: ", label)?;
+        stdout.reset()?;
+        writeln!(&mut stdout, "{}", finding.message)?;
+    }
+
+    let errors = findings
+        .iter()
+        .filter(|finding| finding.severity == Severity::Error)
+        .count();
+
+    if findings.is_empty() {
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+        writeln!(&mut stdout, "No problems found in {:?}", root)?;
+        stdout.reset()?;
+    }
+
+    if errors > 0 {
+        Err(DoctorError::ProblemsFoundError(errors))?
+    }
+
+    Ok(())
+}
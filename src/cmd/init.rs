@@ -1,19 +1,38 @@
 use clap::{Args, ValueEnum};
 use color_eyre::eyre::Result;
-use fs_extra::dir::{self, CopyOptions};
+use fs_extra::dir;
+use glob::Pattern;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use tempfile::{tempdir, TempDir};
 use thiserror::Error;
 
+use crate::apply::{format_patch, plan_changes, ChangeKind, FileChange};
+use crate::archive::{write_archive, ArchiveFormat};
 use crate::cmd::SharedArgs;
 use crate::config::{
-    BASE_DERIVATION_NAME, BOOTSTRAP_DERIVATION_NAME, META_FILE, NAMEPLACEHOLDER, SELF_FLAKE_URI,
+    BASE_DERIVATION_NAME, BOOTSTRAP_DERIVATION_NAME, DEFAULT_NIXPKGS_URL, META_FILE,
+    NAMEPLACEHOLDER, SELF_FLAKE_URI,
 };
-use crate::fs_utils::{regex_in_dir_recursive, reset_permissions};
+use crate::fs_utils::{merge_dirs, regex_in_dir_recursive, remove_matching, reset_permissions};
+use crate::git::{ensure_clean, track_files};
+use crate::history::{apply_recorded, current_command};
+use crate::hooks::{collect_hooks, confirm_hooks, run_hooks};
+use crate::interactive::{ask_existing_flake, InteractiveResolver};
+use crate::lock::{read_lock_source, ProjectLock};
+use crate::merge::merge_contents;
+use crate::merge::nix::merge_nix;
 use crate::nix::NixCmdInterface;
-use crate::parts::{FlakePartTuple, FlakePartsStore};
-use crate::templates::FlakeContext;
+use crate::parts::{
+    normalize_part_reference, store_aliases, FlakePartTuple, FlakePartsStore, PartSelector,
+};
+use crate::presets::{preset_name, Preset};
+use crate::templates::{FlakeContext, FlakeInputsContext};
 
 /// Initialize a new flake-parts projects using the builder.
 #[derive(Debug, Args)]
@@ -30,19 +49,34 @@ pub struct InitCommand {
     pub path: PathBuf,
 
     /// Which parts to include in the project separated by commas. To see
-    /// which ones are available use the `list` subcommand.
+    /// which ones are available use the `list` subcommand. Presets are
+    /// referenced using `@name`, for example `-p @rust-service,treefmt`.
+    /// Parts of stores given an alias using `-I` can be referenced as
+    /// `alias/part`, for example `-p myco/treefmt`.
+    ///
+    /// Parts can also be selected using glob patterns matched against their
+    /// names and flake uris, for example `-p 'gh-actions-*'`, and excluded
+    /// by prefixing them with `!`, for example `-p '+github,!gh-dependabot'`.
+    /// Excluded parts are never added, not even as dependencies.
     #[arg(
         short = 'p',
         long = "parts",
-        required = true,
+        required_unless_present = "from",
         value_delimiter = ',',
         verbatim_doc_comment
     )]
     pub parts: Vec<String>,
 
-    /// Strategy to use when encountering already existing files
-    #[arg(value_enum, short, long, default_value = "skip", verbatim_doc_comment)]
-    pub strategy: InitStrategy,
+    /// Strategy to use when encountering already existing files, defaults
+    /// to `skip` unless configured otherwise in the user configuration.
+    #[arg(
+        value_enum,
+        short,
+        long,
+        env = "FLAKE_PARTS_BUILDER_STRATEGY",
+        verbatim_doc_comment
+    )]
+    pub strategy: Option<InitStrategy>,
 
     /// Force initialization in case of conflicting parts. Note that in such
     /// cases you should probably also pass a merging strategy that fits your
@@ -68,9 +102,204 @@ pub struct InitCommand {
     /// a non-empty directory
     #[arg(long = "force", default_value_t = false, verbatim_doc_comment)]
     pub force: bool,
+
+    /// Interactively decide what to do with every file that already exists
+    /// in the target directory and differs from the part version (keep it,
+    /// take the part version, show a diff, merge it or open it in `$EDITOR`).
+    /// Takes precedence over `--strategy` and `--force` for such files.
+    #[arg(long = "interactive", default_value_t = false, verbatim_doc_comment)]
+    pub interactive: bool,
+
+    /// Allow modifying files with uncommitted changes in case the target
+    /// directory is inside of a git repository.
+    #[arg(long = "allow-dirty", default_value_t = false, verbatim_doc_comment)]
+    pub allow_dirty: bool,
+
+    /// Stage all generated files in git, initializing a new repository in
+    /// the target directory if it isn't already inside of one. Nix flakes
+    /// ignore untracked files, so without this you'll have to `git add`
+    /// them manually before evaluating the flake.
+    #[arg(long = "git", default_value_t = false, verbatim_doc_comment)]
+    pub git: bool,
+
+    /// Only mark the generated files using `git add --intent-to-add`
+    /// instead of fully staging them.
+    #[arg(
+        long = "intent-to-add",
+        default_value_t = false,
+        requires = "git",
+        conflicts_with = "commit",
+        verbatim_doc_comment
+    )]
+    pub intent_to_add: bool,
+
+    /// Commit the generated files with the given message, the applied
+    /// parts are listed in the commit body. Implies `--git`.
+    #[arg(long = "commit", value_name = "MSG", verbatim_doc_comment)]
+    pub commit: Option<String>,
+
+    /// Don't modify the target directory, instead write a git-format patch
+    /// with all of the changes to the given file (`-` for stdout). The patch
+    /// can be reviewed and later applied using `git apply`.
+    #[arg(
+        long = "emit-patch",
+        value_name = "FILE",
+        conflicts_with_all = ["git", "commit"],
+        verbatim_doc_comment
+    )]
+    pub emit_patch: Option<PathBuf>,
+
+    /// Don't modify the target directory, instead package the new project
+    /// into an archive at the given path (`-` for stdout). The format is
+    /// inferred from the extension, that is, `.tar`, `.tar.gz`/`.tgz` or
+    /// `.zip` (stdout always uses `.tar.gz`). The name of the target
+    /// directory is used as the top-level directory of the archive.
+    ///
+    /// NOTE: only supported by the `init` command.
+    #[arg(
+        long = "archive",
+        value_name = "FILE",
+        conflicts_with_all = ["emit_patch", "interactive", "git", "commit"],
+        verbatim_doc_comment
+    )]
+    pub archive: Option<PathBuf>,
+
+    /// Run the post-apply hooks declared by the parts (eg. `direnv allow`)
+    /// without asking for confirmation.
+    #[arg(long = "run-hooks", default_value_t = false, verbatim_doc_comment)]
+    pub run_hooks: bool,
+
+    /// Never run the post-apply hooks declared by the parts.
+    #[arg(
+        long = "no-hooks",
+        default_value_t = false,
+        conflicts_with = "run_hooks",
+        verbatim_doc_comment
+    )]
+    pub no_hooks: bool,
+
+    /// What to do in case the target directory already contains
+    /// a `flake.nix`. Defaults to `refuse`, unless `--interactive` is passed,
+    /// in which case you'll be asked.
+    #[arg(value_enum, long = "existing-flake", verbatim_doc_comment)]
+    pub existing_flake: Option<ExistingFlake>,
+
+    /// Reproduce the selection of another project, that is, its parts,
+    /// parts stores and `nixpkgs` input. Accepts the directory of the
+    /// project, a path to its `.flake-parts-builder/lock.json` file or
+    /// an `http(s)://` url of one. Parts passed using `--parts` are added
+    /// on top of the selection.
+    ///
+    /// NOTE: only supported by the `init` command.
+    #[arg(long = "from", value_name = "SOURCE", verbatim_doc_comment)]
+    pub from: Option<String>,
+
+    /// Url of the `nixpkgs` input of the generated `flake.nix`, defaults
+    /// to `github:nixos/nixpkgs/nixos-unstable`.
+    #[arg(
+        long = "nixpkgs",
+        env = "FLAKE_PARTS_BUILDER_NIXPKGS",
+        value_name = "URL",
+        verbatim_doc_comment
+    )]
+    pub nixpkgs: Option<String>,
+
+    /// Files of the selected parts that shouldn't be added to the project,
+    /// separated by commas. These are globs relative to the project root,
+    /// for example `README.md,flake-parts/pkgs/example.nix`. The excluded
+    /// globs are recorded in the project so that adding other parts later
+    /// doesn't reintroduce them.
+    #[arg(
+        long = "exclude-files",
+        value_name = "GLOBS",
+        value_delimiter = ',',
+        verbatim_doc_comment
+    )]
+    pub exclude_files: Vec<String>,
+
+    /// Presets defined in the user configuration or in the project
+    /// manifest, these take precedence over the presets of the stores.
+    #[arg(skip)]
+    pub presets: BTreeMap<String, Preset>,
+}
+
+impl InitCommand {
+    /// Adds the selection recorded in `lock` to the one passed by the user,
+    /// explicitly passed options take precedence.
+    pub fn apply_lock(&mut self, lock: ProjectLock) -> Result<()> {
+        for store in lock.stores {
+            if !self.shared_args.parts_stores.contains(&store) {
+                self.shared_args.parts_stores.push(store);
+            }
+        }
+        self.parts.extend(lock.parts);
+        if self.shared_args.disable_base_choice()?.is_none() {
+            self.shared_args.disable_base_parts |= lock.disable_base;
+        }
+        self.nixpkgs = self.nixpkgs.take().or(lock.nixpkgs);
+        for glob in lock.exclude_files {
+            if !self.exclude_files.contains(&glob) {
+                self.exclude_files.push(glob);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the `@name` references in `parts` with the parts of the
+    /// presets. Options that weren't set otherwise default to the ones
+    /// carried by the presets.
+    pub fn expand_presets(
+        &mut self,
+        stores: &[FlakePartsStore],
+    ) -> Result<(), PartsTuplesParsingError> {
+        let aliases = store_aliases(stores);
+        let mut parts = Vec::new();
+        for part in std::mem::take(&mut self.parts) {
+            let Some(name) = preset_name(&part) else {
+                parts.push(part);
+                continue;
+            };
+
+            let (preset, store) = match self.presets.get(name) {
+                Some(preset) => (preset.clone(), None),
+                None => stores
+                    .iter()
+                    .find_map(|store| store.presets.get(name).map(|p| (p.clone(), Some(store))))
+                    .ok_or_else(|| PartsTuplesParsingError::UnknownPresetError(name.to_string()))?,
+            };
+            log::info!("Expanding preset {} to {:?}", name, preset.parts);
+
+            // NOTE parts of store presets are relative to their store
+            parts.extend(preset.parts.iter().map(|part| match store {
+                Some(store) => normalize_part_reference(part, &store.flake_uri, &aliases),
+                None => part.clone(),
+            }));
+            self.strategy = self.strategy.or(preset.strategy);
+            self.nixpkgs = self.nixpkgs.take().or(preset.nixpkgs);
+        }
+
+        self.parts = parts;
+        Ok(())
+    }
+
+    /// Strategy used for already existing files, `skip` by default.
+    pub fn strategy(&self) -> InitStrategy {
+        self.strategy.unwrap_or(InitStrategy::Skip)
+    }
+
+    /// Strategy used when copying the prepared project into the target
+    /// directory, `--force` always overwrites already existing files.
+    pub fn target_strategy(&self) -> InitStrategy {
+        if self.force {
+            InitStrategy::Overwrite
+        } else {
+            self.strategy()
+        }
+    }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, ValueEnum)]
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum InitStrategy {
     /// Skip file if already present in the filesystem
     #[clap(verbatim_doc_comment)]
@@ -81,12 +310,53 @@ pub enum InitStrategy {
     Overwrite,
 
     /// Try to merge file if already present in the filesystem.
-    /// This will use a diff like patching algorithm and may fail
-    /// in case of conflicts. (TODO not public yet)
+    /// This uses built-in merge handlers keyed on the file name
+    /// (line-set union for ignore files, deduplicated append for
+    /// `.envrc`, merging by keys for YAML files and by attribute
+    /// paths for `.nix` files), files without a handler are skipped.
     #[clap(verbatim_doc_comment)]
     Merge,
 }
 
+impl InitStrategy {
+    /// Resolves a file that's present on both sides, `ours` being the
+    /// already existing version. Returns `None` if it should be left as is.
+    pub fn resolve(&self, path: &Path, ours: &[u8], theirs: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Self::Skip => None,
+            Self::Overwrite => Some(theirs.to_vec()),
+            Self::Merge => {
+                let (Ok(ours), Ok(theirs)) =
+                    (std::str::from_utf8(ours), std::str::from_utf8(theirs))
+                else {
+                    log::warn!("Unable to merge binary file {:?}", path);
+                    return None;
+                };
+                merge_contents(path, ours, theirs).map(String::into_bytes)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExistingFlake {
+    /// Abort the initialization
+    Refuse,
+
+    /// Keep the existing `flake.nix` and only merge the new inputs into it
+    Add,
+
+    /// Replace the existing `flake.nix` with a newly rendered one
+    Overwrite,
+}
+
+#[derive(Error, Debug)]
+pub enum ExistingFlakeError {
+    #[error("{0:?} already contains a flake.nix. Pass `--existing-flake add` to keep it and merge the new inputs into it (or use the `add` command for flake-parts projects), or `--existing-flake overwrite` to replace it.")]
+    FlakeExistsError(PathBuf),
+}
+
 #[derive(Error, Debug)]
 pub enum PartsTuplesParsingError {
     #[error("The following user required parts couldn't be resolved: {0:?}")]
@@ -97,6 +367,12 @@ pub enum PartsTuplesParsingError {
 
     #[error("The following dependencies were required but couldn't be resolved: {0:?} Please include the necessary flake-parts stores using the `-I` flag or pass the `--ignore-unresolved-deps` flag to ignore this error and force initialization.")]
     UnresolvedDependenciesError(Vec<String>),
+
+    #[error("Invalid part pattern {0}: {1}")]
+    InvalidPatternError(String, String),
+
+    #[error("Preset @{0} isn't defined in the user configuration, the project manifest nor in any of the parts stores. To see which ones are available use the `list` subcommand.")]
+    UnknownPresetError(String),
 }
 
 pub fn parse_required_parts_tuples<'a>(
@@ -113,24 +389,69 @@ pub fn parse_required_parts_tuples<'a>(
         })
         .collect::<Vec<_>>();
 
-    let user_req_flake_strings = cmd.parts.clone();
+    log::debug!("User requested parts: {:?}", cmd.parts);
+
+    let mut selectors = Vec::new();
+    let mut exclusions = Vec::new();
+    for req in &cmd.parts {
+        let (target, req) = match req.strip_prefix('!') {
+            Some(req) => (&mut exclusions, req),
+            None => (&mut selectors, req.as_str()),
+        };
+        let selector = PartSelector::parse(req).map_err(|err| {
+            PartsTuplesParsingError::InvalidPatternError(req.to_string(), err.to_string())
+        })?;
+        target.push(selector);
+    }
 
-    log::debug!("User requested parts: {:?}", user_req_flake_strings);
+    let missing_parts = selectors
+        .iter()
+        .filter(|selector| {
+            !all_parts_tuples
+                .iter()
+                .any(|part_tuple| selector.matches(part_tuple))
+        })
+        .map(|selector| selector.to_string())
+        .collect::<Vec<_>>();
+
+    if !missing_parts.is_empty() {
+        log::error!("Missing parts: {:?}", missing_parts);
+        return Err(PartsTuplesParsingError::MissingPartsError(missing_parts));
+    }
+
+    for exclusion in &exclusions {
+        if !all_parts_tuples
+            .iter()
+            .any(|part_tuple| exclusion.matches(part_tuple))
+        {
+            log::warn!("Excluded pattern !{} doesn't match any part", exclusion);
+        }
+    }
+
+    // NOTE exclusions take precedence over both the selection and the
+    // dependency resolution
+    let is_excluded = |part_tuple: &FlakePartTuple| {
+        exclusions
+            .iter()
+            .any(|exclusion| exclusion.matches(part_tuple))
+    };
 
     let (resolved_deps, unresolved_deps) = {
         let start_indices: Vec<usize> = all_parts_tuples
             .iter()
             .enumerate()
             .filter(|&(_, part_tuple)| {
-                let flake_uri = part_tuple.to_flake_uri(None);
-                user_req_flake_strings
-                    .iter()
-                    .any(|req| req == &flake_uri || req == &part_tuple.part.name)
+                !is_excluded(part_tuple)
+                    && selectors
+                        .iter()
+                        .any(|selector| selector.matches(part_tuple))
             })
             .map(|(index, _)| index)
             .collect();
 
-        FlakePartTuple::resolve_dependencies_of(&all_parts_tuples, start_indices)
+        FlakePartTuple::resolve_dependencies_excluding(&all_parts_tuples, start_indices, |p| {
+            is_excluded(p)
+        })
     };
 
     if !unresolved_deps.is_empty() {
@@ -139,33 +460,45 @@ pub fn parse_required_parts_tuples<'a>(
         ));
     }
 
-    let all_req_flake_strings = user_req_flake_strings
-        .iter()
-        .chain(resolved_deps.iter())
-        .collect::<Vec<_>>();
-
     log::debug!("Resolved dependencies: {:?}", resolved_deps);
     log::debug!("Unresolved dependencies: {:?}", unresolved_deps);
-    log::debug!("All required parts: {:?}", all_req_flake_strings);
 
-    let final_parts_tuples = all_parts_tuples
+    let (excluded_parts, final_parts_tuples): (Vec<_>, Vec<_>) = all_parts_tuples
         .into_iter()
         .filter(|part_tuple| {
-            let flake_uri = part_tuple.to_flake_uri(None);
-            all_req_flake_strings
+            selectors
                 .iter()
-                .any(|&req| req == &flake_uri || req == &part_tuple.part.name)
+                .any(|selector| selector.matches(part_tuple))
+                || resolved_deps.contains(&part_tuple.to_flake_uri(None))
         })
-        .collect::<Vec<_>>();
+        .partition(|part_tuple| is_excluded(part_tuple));
 
-    let missing_parts =
-        FlakePartTuple::find_missing_parts_in(&final_parts_tuples, &user_req_flake_strings);
+    log::debug!(
+        "All required parts: {:?}",
+        final_parts_tuples
+            .iter()
+            .map(|part_tuple| part_tuple.to_flake_uri(None))
+            .collect::<Vec<_>>()
+    );
 
-    if !missing_parts.is_empty() {
-        log::error!("Missing parts: {:?}", missing_parts);
-        return Err(PartsTuplesParsingError::MissingPartsError(
-            missing_parts.into_iter().cloned().collect::<Vec<_>>(),
-        ));
+    // NOTE collections only exist to pull in their members, so excluding
+    // one of them is expected
+    let aliases = store_aliases(stores);
+    for excluded in &excluded_parts {
+        let excluded_uri = excluded.to_flake_uri(None);
+        for part_tuple in &final_parts_tuples {
+            let depends_on_excluded = part_tuple.part.metadata.dependencies.iter().any(|dep| {
+                normalize_part_reference(dep, &part_tuple.store.flake_uri, &aliases) == excluded_uri
+            });
+
+            if depends_on_excluded && !part_tuple.part.name.contains('+') {
+                log::warn!(
+                    "Excluding {} even though {} depends on it",
+                    excluded_uri,
+                    part_tuple.to_flake_uri(None)
+                );
+            }
+        }
     }
 
     if !cmd.ignore_conflicts {
@@ -194,23 +527,17 @@ pub fn prepare_tmpdir(
     parts_tuples: &Vec<FlakePartTuple>,
     target_name: Option<&str>,
     init_strategy: &InitStrategy,
-    render_flake_nix: bool,
+    exclude_files: &[Pattern],
+    flake_context: Option<&FlakeContext>,
 ) -> Result<()> {
-    // TODO MERGE STRATEGY
     let tmp_path = tmpdir.path();
     for part_tuple in parts_tuples {
+        part_tuple.validate_modes()?;
         log::debug!(
             "Copying the following part into tmpdir: {:?}",
             part_tuple.part.name
         );
-        dir::copy(
-            &part_tuple.part.nix_store_path,
-            tmp_path,
-            &CopyOptions::new()
-                .content_only(true)
-                .skip_exist(init_strategy == &InitStrategy::Skip)
-                .overwrite(init_strategy == &InitStrategy::Overwrite),
-        )?;
+        merge_dirs(&part_tuple.part.nix_store_path, tmp_path, init_strategy)?;
     }
 
     log::debug!("Removing meta file from tmpdir");
@@ -219,15 +546,16 @@ pub fn prepare_tmpdir(
     log::info!("Resetting permissions in tmpdir");
     reset_permissions(tmp_path.to_str().unwrap())?;
 
-    if render_flake_nix {
-        log::info!("Rendering `flake.nix.template` in tmpdir");
-
-        let metadata = parts_tuples
-            .iter()
-            .map(|part_tuple| &part_tuple.part.metadata)
-            .collect::<Vec<_>>();
+    for part_tuple in parts_tuples {
+        for (file, mode) in &part_tuple.part.metadata.modes {
+            let path = tmp_path.join(file);
+            log::debug!("Setting mode {:o} of {:?}", mode.0, path);
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode.0))?;
+        }
+    }
 
-        let flake_context = FlakeContext::from_merged_metadata(&metadata);
+    if let Some(flake_context) = flake_context {
+        log::info!("Rendering `flake.nix.template` in tmpdir");
 
         let rendered = flake_context.render()?;
         fs::write(tmp_path.join("flake.nix"), rendered)?;
@@ -247,10 +575,243 @@ pub fn prepare_tmpdir(
         regex_in_dir_recursive(tmp_path.to_str().unwrap(), NAMEPLACEHOLDER, name)?;
     }
 
+    for removed in remove_matching(tmp_path, exclude_files)? {
+        log::info!("Excluding {:?} from the project", removed);
+    }
+
+    Ok(())
+}
+
+/// Modes of files declared in `meta.nix` of the `parts_tuples`, these are
+/// applied also to the already existing files.
+pub fn declared_modes(parts_tuples: &[FlakePartTuple]) -> BTreeMap<PathBuf, u32> {
+    parts_tuples
+        .iter()
+        .flat_map(|part_tuple| &part_tuple.part.metadata.modes)
+        .map(|(file, mode)| (file.clone(), mode.0))
+        .collect()
+}
+
+/// Refuses to overwrite uncommitted modifications of the files touched by
+/// `changes` (including the lock), unless `--allow-dirty` is passed.
+pub fn ensure_clean_target(cmd: &InitCommand, path: &Path, changes: &[FileChange]) -> Result<()> {
+    if !cmd.allow_dirty {
+        ensure_clean(path, changes.iter().map(|change| change.path.as_path()))?;
+    }
+    Ok(())
+}
+
+/// Plans the changes of the target directory at `path` according to
+/// the strategy and flags passed by the user.
+pub fn plan_target_changes(
+    cmd: &InitCommand,
+    tmpdir: &TempDir,
+    path: &Path,
+    declared_modes: &BTreeMap<PathBuf, u32>,
+) -> Result<Vec<FileChange>> {
+    if !cmd.interactive {
+        let strategy = cmd.target_strategy();
+        let overwrite_flake = cmd.existing_flake == Some(ExistingFlake::Overwrite);
+        let changes = plan_changes(tmpdir.path(), path, declared_modes, |file, ours, theirs| {
+            if overwrite_flake && file == Path::new("flake.nix") {
+                return Ok(Some(theirs.to_vec()));
+            }
+            Ok(strategy.resolve(file, ours, theirs))
+        })?;
+        return Ok(changes);
+    }
+
+    // NOTE prompts go to stderr so that they don't end up in a patch
+    // written to stdout
+    let stdin = std::io::stdin();
+    let mut resolver = InteractiveResolver::new(stdin.lock(), std::io::stderr());
+    let changes = plan_changes(tmpdir.path(), path, declared_modes, |file, ours, theirs| {
+        resolver.resolve(file, ours, theirs)
+    })?;
+    resolver.print_summary()?;
+
+    Ok(changes)
+}
+
+/// Prepares a change of the `flake.nix` in `path` adding all of the inputs
+/// from `flake_context` that aren't present yet. Inputs that are already
+/// defined with a different value are left as they are.
+pub fn flake_inputs_change(
+    path: &Path,
+    flake_context: &FlakeInputsContext,
+) -> Result<Option<FileChange>> {
+    let flake_nix_path = path.join("flake.nix");
+    if !flake_nix_path.exists() {
+        log::warn!("No flake.nix found in {:?}, skipping the inputs", path);
+        return Ok(None);
+    }
+
+    let original = fs::read_to_string(&flake_nix_path)?;
+    let mut flake_nix = original.clone();
+
+    let JsonValue::Object(inputs) = &flake_context.inputs else {
+        return Ok(None);
+    };
+
+    for (name, input) in inputs {
+        let rendered = FlakeInputsContext {
+            inputs: JsonValue::Object([(name.clone(), input.clone())].into_iter().collect()),
+            sources: Default::default(),
+        }
+        .render()?;
+
+        let outcome = merge_nix(
+            &flake_nix,
+            &format!("{{\n  inputs = {{{}  }};\n}}\n", rendered),
+        )?;
+        if outcome.conflicts.is_empty() {
+            flake_nix = outcome.content;
+        } else {
+            log::warn!(
+                "Input `{}` is already defined with a different value, keeping yours",
+                name
+            );
+        }
+    }
+
+    if flake_nix == original {
+        return Ok(None);
+    }
+
+    Ok(Some(FileChange::new(
+        PathBuf::from("flake.nix"),
+        ChangeKind::Modify,
+        flake_nix.into_bytes(),
+    )))
+}
+
+/// Records the applied parts and the stores passed by the user in the
+/// `lock` of the project. The base parts are considered disabled only if
+/// they were disabled by all of the recorded operations.
+pub fn update_lock(
+    lock: &mut ProjectLock,
+    stores: &[String],
+    parts_tuples: &[FlakePartTuple],
+    disable_base: bool,
+) {
+    let parts = parts_tuples
+        .iter()
+        .map(|part_tuple| part_tuple.to_flake_uri(None))
+        .collect::<Vec<_>>();
+
+    lock.disable_base = disable_base && (lock.parts.is_empty() || lock.disable_base);
+    lock.extend(stores, &parts);
+}
+
+/// Writes the `changes` of `path` as a patch to `patch_file`, or to stdout
+/// in case of `-`.
+pub fn write_patch(patch_file: &Path, path: &Path, changes: &[FileChange]) -> Result<()> {
+    let patch = format_patch(path, changes)?;
+
+    if patch_file == Path::new("-") {
+        print!("{}", patch);
+    } else {
+        log::info!(
+            "Writing patch with {} changes to {:?}",
+            changes.len(),
+            patch_file
+        );
+        fs::write(patch_file, patch)?;
+    }
+
+    Ok(())
+}
+
+/// Packages the project prepared in `tmpdir` into an `archive` (or stdout
+/// in case of `-`) using the name of `path` as the top-level directory.
+fn package_tmpdir(tmpdir: &TempDir, path: &Path, archive: &Path) -> Result<()> {
+    let format = ArchiveFormat::from_path(archive)?;
+    let prefix = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "project".to_string());
+
+    if archive == Path::new("-") {
+        write_archive(tmpdir.path(), &prefix, format, std::io::stdout().lock())?.flush()?;
+    } else {
+        write_archive(tmpdir.path(), &prefix, format, fs::File::create(archive)?)?.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Stages (and optionally commits) the applied `changes` in git if
+/// requested by the user.
+pub fn track_target_changes(
+    cmd: &InitCommand,
+    path: &Path,
+    changes: &[FileChange],
+    parts_tuples: &[FlakePartTuple],
+) -> Result<()> {
+    if !cmd.git && cmd.commit.is_none() {
+        return Ok(());
+    }
+
+    let files = changes
+        .iter()
+        .map(|change| change.path.clone())
+        .collect::<Vec<_>>();
+
+    let message = cmd.commit.as_ref().map(|message| {
+        let parts = parts_tuples
+            .iter()
+            .map(|part_tuple| format!("- {}", part_tuple.to_flake_uri(None)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!("{}\n\nApplied flake-parts:\n{}", message, parts)
+    });
+
+    track_files(path, &files, cmd.intent_to_add, message.as_deref())
+}
+
+/// Runs the hooks declared by the parts in the target directory if allowed
+/// by the user. Failing hooks are only reported, the applied changes stay.
+pub fn run_target_hooks(
+    cmd: &InitCommand,
+    path: &Path,
+    parts_tuples: &[FlakePartTuple],
+) -> Result<()> {
+    let hooks = collect_hooks(parts_tuples);
+    if hooks.is_empty() || cmd.no_hooks {
+        return Ok(());
+    }
+
+    if !cmd.run_hooks {
+        let stdin = std::io::stdin();
+        if !confirm_hooks(&hooks, stdin.lock(), std::io::stdout())? {
+            log::info!("Skipping {} hooks", hooks.len());
+            return Ok(());
+        }
+    }
+
+    let failed = run_hooks(&hooks, path, std::io::stdout())?;
+    if failed > 0 {
+        log::warn!(
+            "{} of {} hooks failed, the generated files were kept",
+            failed,
+            hooks.len()
+        );
+    }
+
     Ok(())
 }
 
 pub fn init(mut cmd: InitCommand, nix_cmd: impl NixCmdInterface) -> Result<()> {
+    if let Some(source) = cmd.from.clone() {
+        log::info!("Reproducing the selection of {}", source);
+        let lock = read_lock_source(&source, &nix_cmd)?;
+        log::debug!("Selection to reproduce: {:?}", lock);
+        cmd.apply_lock(lock)?;
+    }
+
+    // NOTE only the stores passed by the user are recorded in the lock
+    let user_stores = cmd.shared_args.parts_stores.clone();
+
     if !cmd.shared_args.disable_base_parts {
         log::info!("Adding base parts store to `cmd.shared_args.parts_stores`");
 
@@ -286,16 +847,47 @@ pub fn init(mut cmd: InitCommand, nix_cmd: impl NixCmdInterface) -> Result<()> {
             .collect::<Vec<_>>()
     );
 
+    cmd.expand_presets(&stores)?;
     let parts_tuples = parse_required_parts_tuples(&cmd, &stores)?;
 
     let path = cmd.path.canonicalize().unwrap_or_else(|_| cmd.path.clone());
     log::debug!("Full user provided path: {:?}", path);
 
-    if !path.exists() {
+    if !path.exists() && cmd.emit_patch.is_none() && cmd.archive.is_none() {
         log::info!("Provided path doesn't exist, creating it");
         dir::create_all(&path, false)?;
     }
 
+    if path.join("flake.nix").exists() && cmd.archive.is_none() {
+        let existing_flake = match cmd.existing_flake {
+            Some(existing_flake) => existing_flake,
+            None if cmd.interactive => {
+                let stdin = std::io::stdin();
+                ask_existing_flake(&path, stdin.lock(), std::io::stderr())?
+            }
+            None => ExistingFlake::Refuse,
+        };
+        cmd.existing_flake = Some(existing_flake);
+
+        if existing_flake == ExistingFlake::Refuse {
+            Err(ExistingFlakeError::FlakeExistsError(path.clone()))?
+        }
+    }
+
+    let nixpkgs_url = cmd
+        .nixpkgs
+        .clone()
+        .unwrap_or_else(|| DEFAULT_NIXPKGS_URL.to_string());
+    let flake_context =
+        FlakeContext::from_parts_tuples(&parts_tuples).with_nixpkgs_url(&nixpkgs_url);
+
+    let mut lock = match cmd.archive {
+        Some(_) => ProjectLock::default(),
+        None => ProjectLock::read(&path)?.unwrap_or_default(),
+    };
+    // NOTE files excluded by previous operations are never reintroduced
+    lock.extend_exclude_files(&cmd.exclude_files);
+
     let tmpdir = tempdir()?;
     log::info!("Preparing new project in a tmpdir at {:?}", tmpdir.path());
     prepare_tmpdir(
@@ -303,19 +895,64 @@ pub fn init(mut cmd: InitCommand, nix_cmd: impl NixCmdInterface) -> Result<()> {
         &tmpdir,
         &parts_tuples,
         path.file_name().map(|osstr| osstr.to_str().unwrap()),
-        &cmd.strategy,
-        true,
+        &cmd.strategy(),
+        &lock.exclude_patterns()?,
+        Some(&flake_context),
     )?;
 
+    update_lock(
+        &mut lock,
+        &user_stores,
+        &parts_tuples,
+        cmd.shared_args.disable_base_parts,
+    );
+    // NOTE the nixpkgs input of an already existing flake.nix isn't touched
+    if cmd.existing_flake != Some(ExistingFlake::Add) {
+        lock.nixpkgs = Some(nixpkgs_url);
+    }
+
+    if let Some(archive) = &cmd.archive {
+        let lock_path = tmpdir.path().join(ProjectLock::relative_path());
+        dir::create_all(lock_path.parent().unwrap(), false)?;
+        fs::write(lock_path, lock.to_json()?)?;
+        return package_tmpdir(&tmpdir, &path, archive);
+    }
+
+    // NOTE the rendered flake.nix is replaced by the new inputs merged
+    // into the existing one
+    let rendered_flake = tmpdir.path().join("flake.nix");
+    if cmd.existing_flake == Some(ExistingFlake::Add) && rendered_flake.exists() {
+        fs::remove_file(rendered_flake)?;
+    }
+
     log::info!("Project successfully prepared in tmpdir, now copying to target directory");
-    dir::copy(
-        &tmpdir,
-        &cmd.path,
-        &CopyOptions::new()
-            .content_only(true)
-            .skip_exist(!cmd.force)
-            .overwrite(cmd.force),
-    )?;
+    let mut changes = plan_target_changes(&cmd, &tmpdir, &path, &declared_modes(&parts_tuples))?;
+
+    if cmd.existing_flake == Some(ExistingFlake::Add) {
+        let metadata = parts_tuples
+            .iter()
+            .map(|part_tuple| &part_tuple.part.metadata)
+            .collect::<Vec<_>>();
+        let flake_context = FlakeInputsContext::from_merged_metadata(&metadata);
+
+        if let Some(change) = flake_inputs_change(&path, &flake_context)? {
+            log::info!("Merging the new inputs into the existing flake.nix");
+            changes.push(change);
+        }
+    }
+
+    if let Some(change) = lock.change(&path)? {
+        changes.push(change);
+    }
+
+    if let Some(patch_file) = &cmd.emit_patch {
+        return write_patch(patch_file, &path, &changes);
+    }
+
+    ensure_clean_target(&cmd, &path, &changes)?;
+    apply_recorded(&path, &current_command(), &changes)?;
+    track_target_changes(&cmd, &path, &changes, &parts_tuples)?;
+    run_target_hooks(&cmd, &path, &parts_tuples)?;
 
     Ok(())
 }
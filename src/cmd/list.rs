@@ -1,5 +1,6 @@
 use clap::Args;
 use color_eyre::eyre::Result;
+use std::collections::BTreeMap;
 use std::io::Write;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
@@ -7,12 +8,17 @@ use crate::cmd::SharedArgs;
 use crate::config::{BASE_DERIVATION_NAME, BOOTSTRAP_DERIVATION_NAME, SELF_FLAKE_URI};
 use crate::nix::NixCmdInterface;
 use crate::parts::FlakePartsStore;
+use crate::presets::Preset;
 
 /// List all available flake-parts in all parts stores provided by the user.
 #[derive(Debug, Args)]
 pub struct ListCommand {
     #[clap(flatten)]
     pub shared_args: SharedArgs,
+
+    /// Presets defined in the user configuration
+    #[arg(skip)]
+    pub presets: BTreeMap<String, Preset>,
 }
 
 pub fn list(mut cmd: ListCommand, nix_cmd: impl NixCmdInterface) -> Result<()> {
@@ -30,39 +36,83 @@ pub fn list(mut cmd: ListCommand, nix_cmd: impl NixCmdInterface) -> Result<()> {
         .push(format!("{}#{}", SELF_FLAKE_URI, BOOTSTRAP_DERIVATION_NAME));
 
     let mut stdout = StandardStream::stdout(ColorChoice::Auto);
+    let mut presets = cmd
+        .presets
+        .iter()
+        .map(|(name, preset)| {
+            (
+                "user configuration".to_string(),
+                name.clone(),
+                preset.clone(),
+            )
+        })
+        .collect::<Vec<_>>();
 
     cmd.shared_args
         .parts_stores
         .iter()
         .try_for_each(|flake_uri| {
+            let store = FlakePartsStore::from_flake_uri(flake_uri, &nix_cmd)?;
+
             stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
-            writeln!(&mut stdout, " # {}", flake_uri)?;
+            match &store.alias {
+                Some(alias) => writeln!(&mut stdout, " # {} ({})", alias, store.flake_uri)?,
+                None => writeln!(&mut stdout, " # {}", store.flake_uri)?,
+            }
 
-            // TODO maybe some error message instead of unwrap?
-            FlakePartsStore::from_flake_uri(flake_uri, &nix_cmd)
-                .unwrap()
-                .parts
-                .iter()
-                .try_for_each(|part| {
-                    // Visually distinguish collections
-                    let color = if part.name.contains('+') {
-                        Color::Cyan
-                    } else {
-                        Color::Red
-                    };
+            let origin = store
+                .alias
+                .clone()
+                .unwrap_or_else(|| store.flake_uri.clone());
+            presets.extend(
+                store
+                    .presets
+                    .iter()
+                    .map(|(name, preset)| (origin.clone(), name.clone(), preset.clone())),
+            );
 
-                    stdout.set_color(ColorSpec::new().set_fg(Some(color)))?;
+            store.parts.iter().try_for_each(|part| {
+                // Visually distinguish collections
+                let color = if part.name.contains('+') {
+                    Color::Cyan
+                } else {
+                    Color::Red
+                };
 
-                    write!(&mut stdout, "  - {}: ", part.name)?;
+                stdout.set_color(ColorSpec::new().set_fg(Some(color)))?;
 
-                    stdout.set_color(ColorSpec::new().set_fg(Some(Color::White)))?;
+                write!(&mut stdout, "  - {}: ", part.name)?;
 
-                    writeln!(&mut stdout, "{}", part.metadata.description)?;
+                stdout.set_color(ColorSpec::new().set_fg(Some(Color::White)))?;
 
-                    Ok(()) as Result<()>
-                })?;
+                writeln!(&mut stdout, "{}", part.metadata.description)?;
+
+                Ok(()) as Result<()>
+            })?;
 
             println!();
-            Ok(())
-        })
+            Ok(()) as Result<()>
+        })?;
+
+    if presets.is_empty() {
+        return Ok(());
+    }
+
+    stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+    writeln!(&mut stdout, " # presets")?;
+
+    for (origin, name, preset) in presets {
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::Magenta)))?;
+        write!(&mut stdout, "  - @{} ({}): ", name, origin)?;
+
+        stdout.set_color(ColorSpec::new().set_fg(Some(Color::White)))?;
+        if !preset.description.is_empty() {
+            write!(&mut stdout, "{} ", preset.description)?;
+        }
+        writeln!(&mut stdout, "[{}]", preset.parts.join(", "))?;
+    }
+    stdout.reset()?;
+    println!();
+
+    Ok(())
 }
@@ -1,8 +1,19 @@
-use clap::Args;
+use clap::builder::{BoolishValueParser, TypedValueParser};
+use clap::{Arg, Args, Command};
+use color_eyre::eyre::Result;
+use std::env;
+use std::ffi::OsStr;
+
+use crate::config::DISABLE_BASE_ENV;
 
 pub mod add;
+pub mod apply;
+pub mod diff;
+pub mod doctor;
 pub mod init;
 pub mod list;
+pub mod sync;
+pub mod undo;
 
 #[derive(Debug, Args)]
 pub struct SharedArgs {
@@ -16,9 +27,16 @@ pub struct SharedArgs {
     /// NOTE: the derivation needs to have the parts stored at
     /// `$out/flake-parts`. You can also use `lib.mkFlakeParts` defined
     /// in `flake.nix` to make this easier.
+    ///
+    /// Stores can be given an alias using `alias=uri`, for example
+    /// `-I myco=github:myco/parts#flake-parts`. Their parts can be then
+    /// referenced as `myco/treefmt`, also in the dependencies of other parts.
+    ///
+    /// Stores listed in the user configuration are always loaded as well.
     #[arg(
         short = 'I',
         long = "include",
+        env = "FLAKE_PARTS_BUILDER_STORES",
         value_delimiter = ',',
         verbatim_doc_comment
     )]
@@ -32,10 +50,59 @@ pub struct SharedArgs {
     /// NOTE: _bootstrap part is always included for the project to
     /// properly function (if you really need to you can override the files
     /// with your own versions)
-    #[arg(long = "disable-base", default_value_t = false, verbatim_doc_comment)]
+    ///
+    /// The default can be also set using the FLAKE_PARTS_BUILDER_DISABLE_BASE
+    /// environment variable (eg. `true`, `1`, `false`, `0`), which takes
+    /// precedence over the manifest and the user configuration.
+    #[arg(
+        long = "disable-base",
+        default_value_t = false,
+        overrides_with = "enable_base_parts",
+        verbatim_doc_comment
+    )]
     pub disable_base_parts: bool,
 
+    /// Use the base parts even if they're disabled by the environment, the
+    /// manifest or the user configuration
+    #[arg(
+        long = "enable-base",
+        default_value_t = false,
+        overrides_with = "disable_base_parts",
+        verbatim_doc_comment
+    )]
+    pub enable_base_parts: bool,
+
     /// Enable verbose logging
     #[arg(short, long, default_value_t = false)]
     pub verbose: bool,
 }
+
+impl SharedArgs {
+    /// Whether the base parts were explicitly disabled (`Some(true)`) or
+    /// enabled (`Some(false)`), the flags take precedence over the
+    /// `FLAKE_PARTS_BUILDER_DISABLE_BASE` environment variable.
+    pub fn disable_base_choice(&self) -> Result<Option<bool>> {
+        self.disable_base_choice_with(env::var_os(DISABLE_BASE_ENV).as_deref())
+    }
+
+    /// Same as `disable_base_choice`, with the value of the environment
+    /// variable passed explicitly.
+    pub fn disable_base_choice_with(&self, env_value: Option<&OsStr>) -> Result<Option<bool>> {
+        if self.disable_base_parts {
+            return Ok(Some(true));
+        }
+        if self.enable_base_parts {
+            return Ok(Some(false));
+        }
+
+        let Some(value) = env_value else {
+            return Ok(None);
+        };
+        let disable = BoolishValueParser::new().parse_ref(
+            &Command::new(env!("CARGO_PKG_NAME")),
+            Some(&Arg::new(DISABLE_BASE_ENV)),
+            value,
+        )?;
+        Ok(Some(disable))
+    }
+}
@@ -0,0 +1,124 @@
+use clap::Args;
+use color_eyre::eyre::Result;
+use serde_json::Value as JsonValue;
+use std::fs;
+use std::path::PathBuf;
+use tempfile::tempdir;
+
+use crate::apply::{ChangeKind, FileChange};
+use crate::cmd::SharedArgs;
+use crate::config::{BASE_DERIVATION_NAME, BOOTSTRAP_DERIVATION_NAME, SELF_FLAKE_URI};
+use crate::doctor::{declared_inputs, installed_parts};
+use crate::git::ensure_clean;
+use crate::history::{apply_recorded, current_command};
+use crate::nix::NixCmdInterface;
+use crate::parts::{FlakePartTuple, FlakePartsStore};
+use crate::project::find_project_root;
+use crate::templates::{find_region, sync_regions, FlakeContext};
+
+/// Regenerate the managed regions of `flake.nix` from the installed parts,
+/// that is, the parts recorded in the project lock.
+///
+/// Only the regions between the `# --- BEGIN GENERATED <NAME> ---` and
+/// `# --- END GENERATED <NAME> ---` markers (the inputs and the binary
+/// caches in `nixConfig`) are rendered again, everything else in the file
+/// is left untouched. Inputs you declare outside of the generated region
+/// are never duplicated into it.
+#[derive(Debug, Args)]
+#[command(verbatim_doc_comment)]
+pub struct SyncCommand {
+    #[clap(flatten)]
+    pub shared_args: SharedArgs,
+
+    /// Path (relative or absolute) inside of the project, the project root
+    /// is discovered by walking up from it
+    #[arg(default_value = ".", verbatim_doc_comment)]
+    pub path: PathBuf,
+
+    /// Proceed even if `flake.nix` has uncommitted changes
+    #[arg(long = "allow-dirty", default_value_t = false)]
+    pub allow_dirty: bool,
+}
+
+pub fn sync(mut cmd: SyncCommand, nix_cmd: impl NixCmdInterface) -> Result<()> {
+    if !cmd.shared_args.disable_base_parts {
+        log::info!("Adding base parts store to `cmd.shared_args.parts_stores`");
+        cmd.shared_args
+            .parts_stores
+            .push(format!("{}#{}", SELF_FLAKE_URI, BASE_DERIVATION_NAME));
+    }
+
+    log::info!("Adding bootstrap parts store to `cmd.shared_args.parts_stores`");
+    cmd.shared_args
+        .parts_stores
+        .push(format!("{}#{}", SELF_FLAKE_URI, BOOTSTRAP_DERIVATION_NAME));
+
+    let root = find_project_root(&cmd.path.canonicalize()?)?;
+    log::debug!("Project root: {:?}", root);
+
+    let stores = cmd
+        .shared_args
+        .parts_stores
+        .iter()
+        .map(|store| FlakePartsStore::from_flake_uri(store, &nix_cmd))
+        .collect::<Result<Vec<_>>>()?;
+
+    let all_parts_tuples = stores
+        .iter()
+        .flat_map(|store| {
+            store
+                .parts
+                .iter()
+                .map(move |part| FlakePartTuple::new(store, part.to_owned()))
+        })
+        .collect::<Vec<_>>();
+    let parts_tuples = installed_parts(&root, all_parts_tuples)?;
+    log::debug!(
+        "Installed parts: {:?}",
+        parts_tuples
+            .iter()
+            .map(|part_tuple| part_tuple.to_flake_uri(None))
+            .collect::<Vec<_>>()
+    );
+
+    let flake_nix = fs::read_to_string(root.join("flake.nix"))?;
+
+    // NOTE inputs declared by the user outside of the generated region
+    // take precedence over the ones requested by the parts
+    let mut outside_region = flake_nix.clone();
+    if let Some(range) = find_region(&flake_nix, "INPUTS") {
+        outside_region.replace_range(range, "");
+    }
+    let user_inputs = declared_inputs(&outside_region)?;
+
+    let mut flake_context = FlakeContext::from_parts_tuples(&parts_tuples);
+    if let JsonValue::Object(inputs) = &mut flake_context.flake_inputs_context.inputs {
+        inputs.retain(|name, _| !user_inputs.contains(name));
+    }
+
+    let tmpdir = tempdir()?;
+    let rendered_path = tmpdir.path().join("flake.nix");
+    fs::write(&rendered_path, flake_context.render()?)?;
+    nix_cmd.nixfmt_file(&rendered_path)?;
+
+    let synced = sync_regions(&flake_nix, &fs::read_to_string(&rendered_path)?)?;
+    if synced == flake_nix {
+        println!("flake.nix is already up to date");
+        return Ok(());
+    }
+
+    let change = FileChange::new(
+        PathBuf::from("flake.nix"),
+        ChangeKind::Modify,
+        synced.into_bytes(),
+    );
+
+    if !cmd.allow_dirty {
+        ensure_clean(&root, [change.path.as_path()])?;
+    }
+
+    apply_recorded(&root, &current_command(), &[change])?;
+    println!("Synchronized the generated regions of flake.nix");
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use clap::Args;
+use color_eyre::eyre::Result;
+use std::path::PathBuf;
+
+use crate::history::History;
+use crate::project::find_project_root;
+
+/// Revert the changes made by previous `init`/`add` operations.
+///
+/// Every `init` and `add` saves the previous versions of the files
+/// it touches to `.flake-parts-builder/history/` in the project. By default
+/// only the most recent operation is reverted. Files modified after the
+/// operation aren't overwritten unless `--force` is passed.
+#[derive(Debug, Args)]
+#[command(verbatim_doc_comment)]
+pub struct UndoCommand {
+    /// Path (relative or absolute) inside of the project, the project root
+    /// is discovered by walking up from it
+    #[arg(default_value = ".", verbatim_doc_comment)]
+    pub path: PathBuf,
+
+    /// Revert all operations up to and including the one with the given
+    /// id, that is, restore the state from before it
+    #[arg(long = "to", verbatim_doc_comment)]
+    pub to: Option<u32>,
+
+    /// Undo the operations even if some of the files they wrote were
+    /// modified since then, discarding the modifications
+    #[arg(short, long, default_value_t = false, verbatim_doc_comment)]
+    pub force: bool,
+
+    /// Only list the recorded operations without reverting anything
+    #[arg(short, long, default_value_t = false)]
+    pub list: bool,
+}
+
+pub fn undo(cmd: UndoCommand) -> Result<()> {
+    let root = find_project_root(&cmd.path.canonicalize()?)?;
+    log::debug!("Project root: {:?}", root);
+
+    let history = History::new(&root);
+
+    if cmd.list {
+        for entry in history.entries()? {
+            println!(
+                "{:>4}  {}  {} ({} files)",
+                entry.id,
+                humantime::format_rfc3339_seconds(entry.time()),
+                entry.command,
+                entry.files.len()
+            );
+        }
+        return Ok(());
+    }
+
+    for entry in history.undo(cmd.to, cmd.force)? {
+        println!("Reverted operation {} `{}`", entry.id, entry.command);
+    }
+
+    Ok(())
+}
@@ -1,7 +1,18 @@
 pub static FLAKE_TEMPLATE: &str = include_str!("assets/flake.nix.template");
 pub static FLAKE_INPUTS_TEMPLATE: &str = include_str!("assets/flake-inputs.nix.template");
 pub static META_FILE: &str = "meta.nix";
+pub static PRESETS_DIR: &str = "presets";
 pub static NAMEPLACEHOLDER: &str = "NAMEPLACEHOLDER";
 pub static BASE_DERIVATION_NAME: &str = "flake-parts";
 pub static BOOTSTRAP_DERIVATION_NAME: &str = "flake-parts-bootstrap";
 pub static SELF_FLAKE_URI: &str = "github:tsandrini/flake-parts-builder";
+pub static STATE_DIR: &str = ".flake-parts-builder";
+pub static HISTORY_DIR: &str = "history";
+pub static LOCK_FILE: &str = "lock.json";
+pub static DEFAULT_NIXPKGS_URL: &str = "github:nixos/nixpkgs/nixos-unstable";
+pub static GENERATED_REGIONS: [&str; 3] = ["INPUTS", "TRUSTED-PUBLIC-KEYS", "SUBSTITUTERS"];
+pub static MANIFEST_FILES: [&str; 2] = ["flake-parts.toml", "flake-parts.nix"];
+pub static USER_CONFIG_DIR: &str = "flake-parts-builder";
+pub static USER_CONFIG_FILE: &str = "config.toml";
+pub static USER_CONFIG_ENV: &str = "FLAKE_PARTS_BUILDER_CONFIG";
+pub static DISABLE_BASE_ENV: &str = "FLAKE_PARTS_BUILDER_DISABLE_BASE";
@@ -0,0 +1,490 @@
+//! Checks auditing an already generated project, these are used by the
+//! `doctor` command to find drift between the project and its parts.
+use color_eyre::eyre::Result;
+use glob::Pattern;
+use regex::Regex;
+use rnix::types::EntryHolder;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::config::META_FILE;
+use crate::fs_utils::matches_any;
+use crate::lock::ProjectLock;
+use crate::merge::nix::{as_attrset, key_segments};
+use crate::merge::MergeError;
+use crate::parts::FlakePartTuple;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Finding {
+    pub fn warning(message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message,
+        }
+    }
+
+    pub fn error(message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            message,
+        }
+    }
+}
+
+/// Installation state of a part in the project, determined by which of
+/// its files are present.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PartStatus {
+    Absent,
+    Installed,
+    Partial { missing: Vec<PathBuf> },
+}
+
+/// Returns the names of the top level inputs declared in `flake_nix`,
+/// both `inputs = { name.url = ...; }` and `inputs.name.url = ...` forms
+/// are supported.
+pub fn declared_inputs(flake_nix: &str) -> Result<BTreeSet<String>, MergeError> {
+    let ast = rnix::parse(flake_nix).as_result()?;
+    let root = as_attrset(&ast.node()).ok_or(MergeError::NixStructureError)?;
+
+    let mut inputs = BTreeSet::new();
+    for entry in root.entries() {
+        let segments = key_segments(&entry);
+
+        match segments.as_slice() {
+            [first, name, ..] if first == "inputs" => {
+                inputs.insert(name.clone());
+            }
+            [first] if first == "inputs" => {
+                let Some(set) = entry.value().and_then(|value| as_attrset(&value)) else {
+                    continue;
+                };
+                for input in set.entries() {
+                    if let Some(name) = key_segments(&input).into_iter().next() {
+                        inputs.insert(name);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(inputs)
+}
+
+/// Whether `path` (relative to the project root) should be skipped when
+/// scanning the project files.
+fn is_skipped(path: &Path) -> bool {
+    path.components().any(|component| {
+        let name = component.as_os_str().to_string_lossy();
+        name.starts_with('.') || name == "result"
+    })
+}
+
+/// Source of a nix expression with its comments replaced by whitespace, as
+/// commented out references don't matter. A `#` inside of a string (eg. in
+/// a flake uri) doesn't start a comment.
+fn strip_comments(contents: &str) -> String {
+    rnix::parse(contents)
+        .node()
+        .descendants_with_tokens()
+        .filter_map(|element| element.into_token())
+        .map(|token| match token.kind() {
+            rnix::SyntaxKind::TOKEN_COMMENT => " ".to_string(),
+            _ => token.text().to_string(),
+        })
+        .collect()
+}
+
+/// Scans all `.nix` files of the project (except `flake.nix` itself) for
+/// `inputs.<name>` and `inputs'.<name>` references. Returns the referenced
+/// input names along with the files referencing them.
+pub fn input_references(root: &Path) -> Result<BTreeMap<String, BTreeSet<PathBuf>>> {
+    let pattern = Regex::new(r"\binputs'?\.([A-Za-z_][A-Za-z0-9_'-]*)")?;
+    let mut references: BTreeMap<String, BTreeSet<PathBuf>> = BTreeMap::new();
+
+    for entry in WalkDir::new(root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| !is_skipped(entry.path().strip_prefix(root).unwrap_or(entry.path())))
+    {
+        let entry = entry?;
+        let relative_path = entry.path().strip_prefix(root)?;
+
+        if !entry.file_type().is_file()
+            || relative_path == Path::new("flake.nix")
+            || entry.path().extension().is_none_or(|ext| ext != "nix")
+        {
+            continue;
+        }
+
+        let code = strip_comments(&fs::read_to_string(entry.path())?);
+        for captures in pattern.captures_iter(&code) {
+            references
+                .entry(captures[1].to_string())
+                .or_default()
+                .insert(relative_path.to_path_buf());
+        }
+    }
+
+    Ok(references)
+}
+
+/// Returns the paths (relative to the project root) of files and
+/// directories in `flake-parts/` that `loadParts` silently ignores because
+/// of their `_` or `.git` prefix. The `_bootstrap.nix` file is expected.
+pub fn ignored_part_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let parts_dir = root.join("flake-parts");
+    if !parts_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut ignored = Vec::new();
+    let mut walker = WalkDir::new(&parts_dir)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter();
+
+    while let Some(entry) = walker.next() {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy();
+
+        if !(name.starts_with('_') || name.starts_with(".git")) {
+            continue;
+        }
+
+        if entry.file_type().is_dir() {
+            walker.skip_current_dir();
+        } else if (entry.depth() == 1 && name == "_bootstrap.nix")
+            || entry.path().extension().is_none_or(|ext| ext != "nix")
+        {
+            continue;
+        }
+
+        ignored.push(entry.path().strip_prefix(root)?.to_path_buf());
+    }
+
+    Ok(ignored)
+}
+
+/// Determines whether the part stored at `part_dir` is installed in the
+/// project at `root`. Only the files in the `flake-parts/` directory of
+/// the part are considered, unless it has none, as the remaining files are
+/// usually shared with other parts.
+pub fn part_status(root: &Path, part_dir: &Path, exclude_files: &[Pattern]) -> Result<PartStatus> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(part_dir).sort_by_file_name() {
+        let entry = entry?;
+        let relative_path = entry.path().strip_prefix(part_dir)?;
+
+        if entry.file_type().is_dir()
+            || relative_path == Path::new(META_FILE)
+            || matches_any(exclude_files, relative_path)
+        {
+            continue;
+        }
+        files.push(relative_path.to_path_buf());
+    }
+
+    if files.iter().any(|file| file.starts_with("flake-parts")) {
+        files.retain(|file| file.starts_with("flake-parts"));
+    }
+
+    let missing = files
+        .iter()
+        .filter(|file| root.join(file).symlink_metadata().is_err())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    Ok(if files.is_empty() || missing.len() == files.len() {
+        PartStatus::Absent
+    } else if missing.is_empty() {
+        PartStatus::Installed
+    } else {
+        PartStatus::Partial { missing }
+    })
+}
+
+/// Selects the parts installed in the project at `root`, that is, the parts
+/// recorded in its lock. Projects without a lock (created by older versions
+/// of the builder) fall back to the presence of the part files, see
+/// `part_status`.
+pub fn installed_parts<'a>(
+    root: &Path,
+    parts_tuples: Vec<FlakePartTuple<'a>>,
+) -> Result<Vec<FlakePartTuple<'a>>> {
+    if let Some(lock) = ProjectLock::read(root)? {
+        return Ok(parts_tuples
+            .into_iter()
+            .filter(|part_tuple| lock.parts.contains(&part_tuple.to_flake_uri(None)))
+            .collect());
+    }
+
+    log::warn!(
+        "No lock found in {:?}, detecting the installed parts by their files",
+        root
+    );
+    let mut installed = Vec::new();
+    for part_tuple in parts_tuples {
+        if part_status(root, &part_tuple.part.nix_store_path, &[])? != PartStatus::Absent {
+            installed.push(part_tuple);
+        }
+    }
+    Ok(installed)
+}
+
+/// Reports inputs referenced by the project files or required by the
+/// installed parts that aren't declared in `flake.nix`.
+pub fn check_inputs(
+    declared: &BTreeSet<String>,
+    references: &BTreeMap<String, BTreeSet<PathBuf>>,
+    required: &BTreeMap<String, BTreeSet<String>>,
+) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    for (input, files) in references {
+        if input == "self" || declared.contains(input) {
+            continue;
+        }
+        findings.push(Finding::error(format!(
+            "`inputs.{}` is referenced in {:?} but isn't declared in flake.nix",
+            input, files
+        )));
+    }
+
+    for (input, parts) in required {
+        if declared.contains(input) || references.contains_key(input) {
+            continue;
+        }
+        findings.push(Finding::error(format!(
+            "Input `{}` is required by {:?} but isn't declared in flake.nix",
+            input, parts
+        )));
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parts::{FlakePart, FlakePartsStore};
+    use tempfile::TempDir;
+
+    fn write(root: &Path, path: &str, contents: &str) -> Result<()> {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_declared_inputs() -> Result<(), MergeError> {
+        let flake_nix = r#"{
+  description = "test";
+  inputs = {
+    nixpkgs.url = "github:nixos/nixpkgs/nixos-unstable";
+    flake-parts = {
+      url = "github:hercules-ci/flake-parts";
+      inputs.nixpkgs-lib.follows = "nixpkgs";
+    };
+  };
+  inputs.treefmt-nix.url = "github:numtide/treefmt-nix";
+  outputs = inputs: { };
+}"#;
+
+        assert_eq!(
+            declared_inputs(flake_nix)?,
+            BTreeSet::from([
+                "flake-parts".to_string(),
+                "nixpkgs".to_string(),
+                "treefmt-nix".to_string(),
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_input_references() -> Result<()> {
+        let root = TempDir::new()?;
+        write(root.path(), "flake.nix", "{ inputs.ignored.url = \"\"; }")?;
+        write(
+            root.path(),
+            "flake-parts/treefmt.nix",
+            "{ inputs, ... }: { imports = [ inputs.treefmt-nix.flakeModule ]; }",
+        )?;
+        write(
+            root.path(),
+            "flake-parts/shells/default.nix",
+            "{ inputs', ... }: inputs'.devenv.packages # inputs.commented",
+        )?;
+        write(
+            root.path(),
+            "flake-parts/pkgs.nix",
+            "{ inputs, ... }: { url = \"github:o/r#x\"; pkgs = inputs.nixpkgs; /* inputs.block */ }",
+        )?;
+        write(root.path(), ".direnv/cache.nix", "inputs.hidden")?;
+
+        let references = input_references(root.path())?;
+        assert_eq!(
+            references.keys().collect::<Vec<_>>(),
+            vec!["devenv", "nixpkgs", "treefmt-nix"]
+        );
+        assert_eq!(
+            references["treefmt-nix"],
+            BTreeSet::from([PathBuf::from("flake-parts/treefmt.nix")])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignored_part_files() -> Result<()> {
+        let root = TempDir::new()?;
+        write(root.path(), "flake-parts/_bootstrap.nix", "{ }")?;
+        write(root.path(), "flake-parts/_disabled.nix", "{ }")?;
+        write(root.path(), "flake-parts/_helpers/lib.nix", "{ }")?;
+        write(root.path(), "flake-parts/pkgs/_old.nix", "{ }")?;
+        write(root.path(), "flake-parts/pkgs/default.nix", "{ }")?;
+        write(root.path(), "flake-parts/pkgs/.gitkeep", "")?;
+
+        assert_eq!(
+            ignored_part_files(root.path())?,
+            vec![
+                PathBuf::from("flake-parts/_disabled.nix"),
+                PathBuf::from("flake-parts/_helpers"),
+                PathBuf::from("flake-parts/pkgs/_old.nix"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_part_status() -> Result<()> {
+        let part = TempDir::new()?;
+        write(part.path(), META_FILE, "{ }")?;
+        write(part.path(), "README.md", "shared")?;
+        write(part.path(), "flake-parts/shells/default.nix", "{ }")?;
+        write(part.path(), "flake-parts/shells/dev.nix", "{ }")?;
+
+        let root = TempDir::new()?;
+        write(root.path(), "README.md", "shared")?;
+        assert_eq!(
+            part_status(root.path(), part.path(), &[])?,
+            PartStatus::Absent
+        );
+
+        write(root.path(), "flake-parts/shells/default.nix", "{ }")?;
+        assert_eq!(
+            part_status(root.path(), part.path(), &[])?,
+            PartStatus::Partial {
+                missing: vec![PathBuf::from("flake-parts/shells/dev.nix")]
+            }
+        );
+
+        write(root.path(), "flake-parts/shells/dev.nix", "{ }")?;
+        assert_eq!(
+            part_status(root.path(), part.path(), &[])?,
+            PartStatus::Installed
+        );
+
+        fs::remove_file(root.path().join("flake-parts/shells/dev.nix"))?;
+        let excluded = vec![Pattern::new("flake-parts/shells/dev.nix")?];
+        assert_eq!(
+            part_status(root.path(), part.path(), &excluded)?,
+            PartStatus::Installed
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_installed_parts() -> Result<()> {
+        let parts_dir = TempDir::new()?;
+        write(parts_dir.path(), "shells/flake-parts/shells.nix", "{ }")?;
+        write(parts_dir.path(), "+github/meta.nix", "{ }")?;
+
+        let part = |name: &str| FlakePart {
+            name: name.to_string(),
+            nix_store_path: parts_dir.path().join(name),
+            metadata: serde_json::from_str("{}").unwrap(),
+        };
+        let store = FlakePartsStore {
+            flake_uri: "github:org/store#flake-parts".to_string(),
+            alias: None,
+            nix_store_path: parts_dir.path().to_path_buf(),
+            parts: vec![part("shells"), part("+github")],
+            presets: BTreeMap::new(),
+        };
+        let installed = |root: &Path| -> Result<Vec<String>> {
+            let parts_tuples = store
+                .parts
+                .iter()
+                .map(|part| FlakePartTuple::new(&store, part.to_owned()))
+                .collect();
+            Ok(installed_parts(root, parts_tuples)?
+                .iter()
+                .map(|part_tuple| part_tuple.part.name.clone())
+                .collect())
+        };
+
+        // NOTE without a lock only parts with files can be detected
+        let root = TempDir::new()?;
+        write(root.path(), "flake-parts/shells.nix", "{ }")?;
+        assert_eq!(installed(root.path())?, vec!["shells"]);
+
+        let lock = ProjectLock {
+            parts: vec!["github:org/store#flake-parts/+github".to_string()],
+            ..Default::default()
+        };
+        write(
+            root.path(),
+            ProjectLock::relative_path().to_str().unwrap(),
+            &lock.to_json()?,
+        )?;
+        assert_eq!(installed(root.path())?, vec!["+github"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_inputs() {
+        let declared = BTreeSet::from(["nixpkgs".to_string()]);
+        let references = BTreeMap::from([
+            (
+                "nixpkgs".to_string(),
+                BTreeSet::from([PathBuf::from("a.nix")]),
+            ),
+            ("self".to_string(), BTreeSet::from([PathBuf::from("a.nix")])),
+            (
+                "treefmt-nix".to_string(),
+                BTreeSet::from([PathBuf::from("flake-parts/treefmt.nix")]),
+            ),
+        ]);
+        let required = BTreeMap::from([
+            (
+                "treefmt-nix".to_string(),
+                BTreeSet::from(["store#treefmt".to_string()]),
+            ),
+            (
+                "devenv".to_string(),
+                BTreeSet::from(["store#devenv".to_string()]),
+            ),
+        ]);
+
+        let findings = check_inputs(&declared, &references, &required);
+        assert_eq!(findings.len(), 2);
+        assert!(findings[0].message.contains("`inputs.treefmt-nix`"));
+        assert!(findings[1].message.contains("`devenv`"));
+    }
+}
@@ -1,21 +1,32 @@
 use color_eyre::eyre::Result;
-use diff::{slice, Result as DiffResult};
-use fs_extra::dir::{self, CopyOptions};
+use diff::Result as DiffResult;
+use glob::{MatchOptions, Pattern, PatternError};
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::fs::{self, File, Permissions};
 use std::io::{self, Read, Write};
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use walkdir::WalkDir;
 
-use crate::config::META_FILE;
+use crate::apply::{apply_changes, plan_changes};
+use crate::cmd::init::InitStrategy;
 
 pub fn reset_permissions(path: &str) -> std::io::Result<()> {
     for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+        // NOTE symlinks don't have permissions of their own
+        if entry.path_is_symlink() {
+            continue;
+        }
+
         let path = entry.path();
         let metadata = fs::metadata(path)?;
 
-        if metadata.is_dir() {
+        // NOTE files copied from the nix store are read-only, we make them
+        // writable while keeping the executable bit of scripts
+        let is_executable = metadata.permissions().mode() & 0o111 != 0;
+        if metadata.is_dir() || (metadata.is_file() && is_executable) {
             fs::set_permissions(path, Permissions::from_mode(0o755))?;
         } else if metadata.is_file() {
             fs::set_permissions(path, Permissions::from_mode(0o644))?;
@@ -45,73 +56,218 @@ pub fn regex_in_dir_recursive(dir: &str, pattern: &str, replacement: &str) -> io
     Ok(())
 }
 
-// TODO might implement a "merging" strategy instead of skipping/overwriting
-// but currently not entirely sure about its use case
-#[allow(dead_code)]
-pub fn merge_files(base: &Path, theirs: &Path, ours: &Path) -> Result<()> {
-    let base_content = fs::read_to_string(base)?;
-    let their_content = fs::read_to_string(theirs)?;
-    let our_content = fs::read_to_string(ours)?;
-
-    let base_lines = base_content.lines().collect::<Vec<_>>();
-    let their_lines = their_content.lines().collect::<Vec<_>>();
-    let our_lines = our_content.lines().collect::<Vec<_>>();
-
-    let diffs = slice(&base_lines, &their_lines);
-
-    let mut merged_lines = Vec::new();
-    let mut our_iter = our_lines.iter();
-    for diff in diffs {
-        match diff {
-            DiffResult::Both(_, line) => {
-                // Both versions are the same
-                merged_lines.push(*line);
-                our_iter.next(); // Move along ours as well
-            }
-            DiffResult::Left(_) => {
-                // Line is only in base, so we take from ours
-                if let Some(line) = our_iter.next() {
-                    merged_lines.push(*line);
-                }
-            }
-            DiffResult::Right(line) => {
-                // Line is only in theirs
-                merged_lines.push(*line);
+pub fn is_symlink(path: &Path) -> bool {
+    path.symlink_metadata()
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+/// Reads the contents of a file, or the target of a symlink without
+/// following it.
+pub fn read_entry(path: &Path) -> io::Result<Vec<u8>> {
+    if is_symlink(path) {
+        return Ok(fs::read_link(path)?.as_os_str().as_bytes().to_vec());
+    }
+    fs::read(path)
+}
+
+/// Copies a file, or recreates a symlink without following it.
+pub fn copy_entry(from: &Path, to: &Path) -> io::Result<()> {
+    if is_symlink(from) {
+        return symlink(fs::read_link(from)?, to);
+    }
+    fs::copy(from, to).map(|_| ())
+}
+
+/// Lexically resolves `.` and `..` components without touching the filesystem.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
             }
+            component => normalized.push(component),
         }
     }
-    // Append remaining lines from ours
-    for line in our_iter {
-        merged_lines.push(*line);
+    normalized
+}
+
+/// Relative path leading from the directory `base` to `target`, both
+/// paths are expected to be normalized.
+pub fn relative_path(base: &Path, target: &Path) -> PathBuf {
+    let base_components = base.components().collect::<Vec<_>>();
+    let target_components = target.components().collect::<Vec<_>>();
+    let common = base_components
+        .iter()
+        .zip(&target_components)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..base_components.len() {
+        relative.push("..");
     }
+    for component in &target_components[common..] {
+        relative.push(component);
+    }
+    relative
+}
 
-    fs::write(ours, merged_lines.join("\n"))?;
-    Ok(())
+/// Compiles glob patterns of paths relative to the project root.
+pub fn compile_globs(globs: &[String]) -> Result<Vec<Pattern>, PatternError> {
+    globs.iter().map(|glob| Pattern::new(glob)).collect()
 }
 
-// TODO
-#[allow(dead_code)]
-pub fn merge_dirs(src: &Path, dst: &Path, options: &CopyOptions) -> Result<()> {
-    for entry in WalkDir::new(src)
-        .into_iter()
-        .filter_entry(|e| e.file_name() != META_FILE)
-    {
+/// Whether the `relative_path` is matched by any of the `patterns`, `*`
+/// doesn't match across directories (use `**` for that).
+pub fn matches_any(patterns: &[Pattern], relative_path: &Path) -> bool {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    patterns
+        .iter()
+        .any(|pattern| pattern.matches_path_with(relative_path, options))
+}
+
+/// Removes the files and directories inside of `dir` matched by the
+/// `patterns`, returns their paths relative to `dir`.
+pub fn remove_matching(dir: &Path, patterns: &[Pattern]) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    if patterns.is_empty() {
+        return Ok(removed);
+    }
+
+    let mut walker = WalkDir::new(dir)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter();
+    while let Some(entry) = walker.next() {
         let entry = entry?;
-        let target_path = dst.join(entry.path().strip_prefix(src)?);
+        let relative_path = entry.path().strip_prefix(dir)?.to_path_buf();
+        if !matches_any(patterns, &relative_path) {
+            continue;
+        }
 
-        if target_path.exists() {
-            if entry.path().is_file() {
-                // Attempt to merge files
-                merge_files(entry.path(), &target_path, entry.path())?;
-            }
-        } else if entry.path().is_dir() {
-            dir::create_all(&target_path, false)?;
+        if entry.file_type().is_dir() {
+            walker.skip_current_dir();
+            fs::remove_dir_all(entry.path())?;
         } else {
-            // TODO copy_with_progress?
-            dir::copy(entry.path(), &target_path, options)?;
+            fs::remove_file(entry.path())?;
         }
+        removed.push(relative_path);
     }
-    Ok(())
+
+    Ok(removed)
+}
+
+/// Recursively copies the content of `src` into `dst`, resolving already
+/// existing files according to the provided `strategy`.
+pub fn merge_dirs(src: &Path, dst: &Path, strategy: &InitStrategy) -> Result<()> {
+    let changes = plan_changes(src, dst, &BTreeMap::new(), |path, ours, theirs| {
+        Ok(strategy.resolve(path, ours, theirs))
+    })?;
+    apply_changes(dst, &changes)
+}
+
+/// Renders the hunks of a unified diff between `old` and `new` with the
+/// given number of context lines. Returns an empty string if there
+/// are no differences.
+pub fn unified_diff(old: &str, new: &str, context: usize) -> String {
+    let old_lines = old.lines().collect::<Vec<_>>();
+    let new_lines = new.lines().collect::<Vec<_>>();
+    let ops = diff::slice(&old_lines, &new_lines);
+
+    let changed = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| !matches!(op, DiffResult::Both(_, _)))
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+
+    let Some(&first_change) = changed.first() else {
+        return String::new();
+    };
+
+    // 0-based line numbers in `old` and `new` before each operation
+    let mut positions = Vec::with_capacity(ops.len());
+    let (mut old_line, mut new_line) = (0, 0);
+    for op in &ops {
+        positions.push((old_line, new_line));
+        match op {
+            DiffResult::Left(_) => old_line += 1,
+            DiffResult::Right(_) => new_line += 1,
+            DiffResult::Both(_, _) => {
+                old_line += 1;
+                new_line += 1;
+            }
+        }
+    }
+    let (old_total, new_total) = (old_line, new_line);
+
+    let mut hunks = Vec::new();
+    let (mut start, mut end) = (first_change, first_change);
+    for &index in &changed[1..] {
+        if index - end > 2 * context {
+            hunks.push((start, end));
+            start = index;
+        }
+        end = index;
+    }
+    hunks.push((start, end));
+
+    let format_range = |start: usize, count: usize| match count {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, count),
+    };
+
+    let mut rendered = String::new();
+    for (first, last) in hunks {
+        let from = first.saturating_sub(context);
+        let to = (last + context + 1).min(ops.len());
+        let hunk = &ops[from..to];
+
+        let (old_start, new_start) = positions[from];
+        let old_count = hunk
+            .iter()
+            .filter(|op| !matches!(op, DiffResult::Right(_)))
+            .count();
+        let new_count = hunk
+            .iter()
+            .filter(|op| !matches!(op, DiffResult::Left(_)))
+            .count();
+
+        rendered.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            format_range(old_start, old_count),
+            format_range(new_start, new_count)
+        ));
+
+        for (op, &(old_line, new_line)) in hunk.iter().zip(&positions[from..to]) {
+            let (prefix, line, is_last) = match op {
+                DiffResult::Left(line) => {
+                    ('-', line, old_line + 1 == old_total && !old.ends_with('\n'))
+                }
+                DiffResult::Right(line) => {
+                    ('+', line, new_line + 1 == new_total && !new.ends_with('\n'))
+                }
+                DiffResult::Both(line, _) => {
+                    (' ', line, old_line + 1 == old_total && !old.ends_with('\n'))
+                }
+            };
+            rendered.push(prefix);
+            rendered.push_str(line);
+            rendered.push('\n');
+            if is_last {
+                rendered.push_str("\\ No newline at end of file\n");
+            }
+        }
+    }
+
+    rendered
 }
 
 #[cfg(test)]
@@ -120,6 +276,41 @@ mod tests {
     use std::fs::{create_dir_all, File};
     use tempfile::TempDir;
 
+    #[test]
+    fn test_remove_matching() -> Result<()> {
+        let dir = TempDir::new()?;
+        create_dir_all(dir.path().join("flake-parts/pkgs"))?;
+        create_dir_all(dir.path().join("docs/nested"))?;
+        for file in [
+            "README.md",
+            "flake-parts/README.md",
+            "flake-parts/pkgs/example.nix",
+            "flake-parts/pkgs/default.nix",
+            "docs/nested/index.md",
+        ] {
+            File::create(dir.path().join(file))?;
+        }
+
+        let patterns = compile_globs(&[
+            "*.md".to_string(),
+            "flake-parts/pkgs/example.nix".to_string(),
+            "docs".to_string(),
+        ])?;
+        assert_eq!(
+            remove_matching(dir.path(), &patterns)?,
+            vec![
+                PathBuf::from("README.md"),
+                PathBuf::from("docs"),
+                PathBuf::from("flake-parts/pkgs/example.nix"),
+            ]
+        );
+
+        assert!(dir.path().join("flake-parts/README.md").exists());
+        assert!(dir.path().join("flake-parts/pkgs/default.nix").exists());
+        assert!(!dir.path().join("docs").exists());
+        Ok(())
+    }
+
     #[test]
     fn test_reset_permissions() -> io::Result<()> {
         let temp_dir = TempDir::new()?;
@@ -129,13 +320,21 @@ mod tests {
         // Create a test file with different permissions
         let test_file = test_dir.join("test_file.txt");
         File::create(&test_file)?;
-        fs::set_permissions(&test_file, Permissions::from_mode(0o777))?;
+        fs::set_permissions(&test_file, Permissions::from_mode(0o666))?;
+
+        // Create an executable script, which should stay executable
+        let test_script = test_dir.join("test_script.sh");
+        File::create(&test_script)?;
+        fs::set_permissions(&test_script, Permissions::from_mode(0o555))?;
 
         reset_permissions(test_dir.to_str().unwrap())?;
 
         let metadata = fs::metadata(&test_file)?;
         assert_eq!(metadata.permissions().mode() & 0o777, 0o644);
 
+        let script_metadata = fs::metadata(&test_script)?;
+        assert_eq!(script_metadata.permissions().mode() & 0o777, 0o755);
+
         let dir_metadata = fs::metadata(&test_dir)?;
         assert_eq!(dir_metadata.permissions().mode() & 0o777, 0o755);
 
@@ -155,7 +354,7 @@ mod tests {
         File::create(&root_file)?;
         File::create(&nested_file)?;
 
-        fs::set_permissions(&root_file, Permissions::from_mode(0o777))?;
+        fs::set_permissions(&root_file, Permissions::from_mode(0o666))?;
         fs::set_permissions(&nested_file, Permissions::from_mode(0o600))?;
         fs::set_permissions(&nested_dir, Permissions::from_mode(0o700))?;
 
@@ -223,4 +422,144 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_merge_dirs_skip() -> Result<()> {
+        let src = TempDir::new()?;
+        let dst = TempDir::new()?;
+        create_dir_all(src.path().join("nested"))?;
+        fs::write(src.path().join("existing.txt"), "new")?;
+        fs::write(src.path().join("nested").join("fresh.txt"), "fresh")?;
+        fs::write(dst.path().join("existing.txt"), "old")?;
+
+        merge_dirs(src.path(), dst.path(), &InitStrategy::Skip)?;
+
+        assert_eq!(fs::read_to_string(dst.path().join("existing.txt"))?, "old");
+        assert_eq!(
+            fs::read_to_string(dst.path().join("nested").join("fresh.txt"))?,
+            "fresh"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_dirs_overwrite() -> Result<()> {
+        let src = TempDir::new()?;
+        let dst = TempDir::new()?;
+        fs::write(src.path().join("existing.txt"), "new")?;
+        fs::write(dst.path().join("existing.txt"), "old")?;
+        fs::set_permissions(
+            dst.path().join("existing.txt"),
+            Permissions::from_mode(0o444),
+        )?;
+
+        merge_dirs(src.path(), dst.path(), &InitStrategy::Overwrite)?;
+
+        assert_eq!(fs::read_to_string(dst.path().join("existing.txt"))?, "new");
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_dirs_merge() -> Result<()> {
+        let src = TempDir::new()?;
+        let dst = TempDir::new()?;
+        fs::write(src.path().join(".gitignore"), "result\n.direnv\n")?;
+        fs::write(src.path().join("flake.nix"), "{ }")?;
+        fs::write(dst.path().join(".gitignore"), "result\n")?;
+        fs::write(dst.path().join("flake.nix"), "{ inputs = { }; }")?;
+
+        merge_dirs(src.path(), dst.path(), &InitStrategy::Merge)?;
+        // Merging is idempotent
+        merge_dirs(src.path(), dst.path(), &InitStrategy::Merge)?;
+
+        assert_eq!(
+            fs::read_to_string(dst.path().join(".gitignore"))?,
+            "result\n\n.direnv\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dst.path().join("flake.nix"))?,
+            "{ inputs = { }; }"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+
+        assert_eq!(
+            unified_diff(old, new, 1),
+            "@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n@@ -10 +10,2 @@\n j\n+k\n"
+        );
+    }
+
+    #[test]
+    fn test_unified_diff_new_file() {
+        assert_eq!(
+            unified_diff("", "a\nb", 3),
+            "@@ -0,0 +1,2 @@\n+a\n+b\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn test_unified_diff_no_changes() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n", 3), "");
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(
+            relative_path(Path::new("/part/a/b"), Path::new("/part/c/file")),
+            PathBuf::from("../../c/file")
+        );
+        assert_eq!(
+            relative_path(Path::new("/part"), Path::new("/part/file")),
+            PathBuf::from("file")
+        );
+        assert_eq!(
+            normalize_path(Path::new("/part/a/./../b/file")),
+            PathBuf::from("/part/b/file")
+        );
+    }
+
+    #[test]
+    fn test_merge_dirs_symlinks() -> Result<()> {
+        let store = TempDir::new()?;
+        let part = store.path().join("part");
+        create_dir_all(part.join("bin"))?;
+        fs::write(part.join("script.sh"), "#!/bin/sh")?;
+        fs::write(store.path().join("outside.txt"), "outside")?;
+
+        symlink("../script.sh", part.join("bin").join("relative"))?;
+        symlink(part.join("script.sh"), part.join("bin").join("absolute"))?;
+        symlink(store.path().join("outside.txt"), part.join("outside"))?;
+
+        let dst = TempDir::new()?;
+        merge_dirs(&part, dst.path(), &InitStrategy::Skip)?;
+
+        let bin = dst.path().join("bin");
+        assert_eq!(
+            fs::read_link(bin.join("relative"))?,
+            PathBuf::from("../script.sh")
+        );
+        assert_eq!(
+            fs::read_link(bin.join("absolute"))?,
+            PathBuf::from("../script.sh")
+        );
+        assert!(!is_symlink(&dst.path().join("outside")));
+        assert_eq!(fs::read_to_string(dst.path().join("outside"))?, "outside");
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_dirs_dangling_symlink() -> Result<()> {
+        let part = TempDir::new()?;
+        symlink("/nonexistent/store/path", part.path().join("dangling"))?;
+
+        let dst = TempDir::new()?;
+        assert!(merge_dirs(part.path(), dst.path(), &InitStrategy::Skip).is_err());
+        assert!(fs::read_dir(dst.path())?.next().is_none());
+        Ok(())
+    }
 }
@@ -0,0 +1,296 @@
+use color_eyre::eyre::Result;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use thiserror::Error;
+
+pub struct GitExecutor {
+    git_binary: PathBuf,
+}
+
+#[derive(Error, Debug)]
+pub enum GitExecutorError {
+    #[error("git binary not found")]
+    GitBinaryNotFound,
+
+    #[error("failed to run git command: {0}")]
+    GitCommandError(#[from] std::io::Error),
+
+    #[error("failed to parse the UTF-8 output of git command")]
+    GitUTF8Error(#[from] std::string::FromUtf8Error),
+
+    #[error("git command failed with nonzero status: {0}")]
+    NonzeroStatusError(String),
+}
+
+#[derive(Error, Debug)]
+pub enum DirtyWorkingTreeError {
+    #[error("The following files have uncommitted changes and would be modified: {0:?} Please commit or stash them first, or pass the `--allow-dirty` flag to proceed anyway.")]
+    UncommittedChangesError(Vec<String>),
+}
+
+impl GitExecutor {
+    pub fn new(git_binary: PathBuf) -> Self {
+        Self { git_binary }
+    }
+
+    pub fn from_env() -> Result<Self, GitExecutorError> {
+        let git_binary = std::env::var_os("GIT_BIN_PATH")
+            .map(PathBuf::from)
+            .or_else(|| which::which("git").ok())
+            .ok_or(GitExecutorError::GitBinaryNotFound)?;
+
+        Ok(Self::new(git_binary))
+    }
+
+    fn git_command(&self, dir: &Path) -> Command {
+        let mut cmd = Command::new(&self.git_binary);
+        cmd.arg("-C").arg(dir);
+        cmd
+    }
+
+    fn run(&self, mut command: Command) -> Result<String, GitExecutorError> {
+        let output = command.output()?;
+
+        if !output.status.success() {
+            return Err(GitExecutorError::NonzeroStatusError(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    /// Returns the root of the git work tree containing `dir` or `None`
+    /// if `dir` isn't inside of a git repository.
+    pub fn toplevel(&self, dir: &Path) -> Result<Option<PathBuf>, GitExecutorError> {
+        let mut command = self.git_command(dir);
+        command.args(["rev-parse", "--show-toplevel"]);
+
+        match self.run(command) {
+            Ok(stdout) => Ok(Some(PathBuf::from(stdout.trim()))),
+            Err(GitExecutorError::NonzeroStatusError(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns absolute paths of all modified, staged or untracked files
+    /// in the work tree rooted at `toplevel`.
+    pub fn dirty_files(&self, toplevel: &Path) -> Result<Vec<PathBuf>, GitExecutorError> {
+        let mut command = self.git_command(toplevel);
+        command.args(["status", "--porcelain", "-z", "--untracked-files=all"]);
+
+        Ok(parse_porcelain_status(&self.run(command)?)
+            .into_iter()
+            .map(|path| toplevel.join(path))
+            .collect())
+    }
+
+    pub fn init(&self, dir: &Path) -> Result<(), GitExecutorError> {
+        let mut command = self.git_command(dir);
+        command.arg("init");
+        self.run(command)?;
+        Ok(())
+    }
+
+    /// Stages the `files` (relative to `dir`), or only records the intent
+    /// to add them if `intent_to_add` is set.
+    pub fn add(
+        &self,
+        dir: &Path,
+        files: &[PathBuf],
+        intent_to_add: bool,
+    ) -> Result<(), GitExecutorError> {
+        let mut command = self.git_command(dir);
+        command.arg("add");
+        if intent_to_add {
+            command.arg("--intent-to-add");
+        }
+        command.arg("--").args(files);
+        self.run(command)?;
+        Ok(())
+    }
+
+    /// Commits only the `files` (relative to `dir`), leaving anything else
+    /// the user might have staged untouched.
+    pub fn commit(
+        &self,
+        dir: &Path,
+        message: &str,
+        files: &[PathBuf],
+    ) -> Result<(), GitExecutorError> {
+        let mut command = self.git_command(dir);
+        command
+            .args(["commit", "--message", message, "--"])
+            .args(files);
+        self.run(command)?;
+        Ok(())
+    }
+}
+
+/// Parses the output of `git status --porcelain -z`, returning the paths
+/// relative to the root of the work tree.
+fn parse_porcelain_status(output: &str) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    let mut records = output.split('\0').filter(|record| !record.is_empty());
+
+    while let Some(record) = records.next() {
+        let (status, path) = record.split_at(3.min(record.len()));
+        paths.push(PathBuf::from(path));
+
+        // NOTE renames and copies are followed by an additional record
+        // with the original path
+        if status.starts_with('R') || status.starts_with('C') {
+            if let Some(original) = records.next() {
+                paths.push(PathBuf::from(original));
+            }
+        }
+    }
+
+    paths
+}
+
+/// Makes sure that none of the `files` (relative to `root`) have uncommitted
+/// changes in case `root` is inside of a git repository.
+pub fn ensure_clean<'a>(root: &Path, files: impl IntoIterator<Item = &'a Path>) -> Result<()> {
+    let git = match GitExecutor::from_env() {
+        Ok(git) => git,
+        Err(err) => {
+            log::warn!("Skipping the check for uncommitted changes: {}", err);
+            return Ok(());
+        }
+    };
+
+    let Some(toplevel) = git.toplevel(root)? else {
+        log::debug!("{:?} isn't inside of a git repository", root);
+        return Ok(());
+    };
+
+    let dirty_files = git.dirty_files(&toplevel)?;
+    log::debug!("Files with uncommitted changes: {:?}", dirty_files);
+
+    let touched_dirty_files = files
+        .into_iter()
+        .filter(|file| dirty_files.contains(&root.join(file)))
+        .map(|file| file.display().to_string())
+        .collect::<Vec<_>>();
+
+    if !touched_dirty_files.is_empty() {
+        Err(DirtyWorkingTreeError::UncommittedChangesError(
+            touched_dirty_files,
+        ))?
+    }
+
+    Ok(())
+}
+
+/// Makes sure `root` is inside of a git repository, initializing a new
+/// one if necessary, and stages the `files` (relative to `root`). If
+/// a commit `message` is provided, the files are committed as well.
+pub fn track_files(
+    root: &Path,
+    files: &[PathBuf],
+    intent_to_add: bool,
+    message: Option<&str>,
+) -> Result<()> {
+    let git = GitExecutor::from_env()?;
+
+    if git.toplevel(root)?.is_none() {
+        log::info!("Initializing a new git repository in {:?}", root);
+        git.init(root)?;
+    }
+
+    if files.is_empty() {
+        log::info!("No files were changed, nothing to stage");
+        return Ok(());
+    }
+
+    log::info!("Staging {} changed files", files.len());
+    git.add(root, files, intent_to_add)?;
+
+    if let Some(message) = message {
+        log::info!("Committing {} changed files", files.len());
+        git.commit(root, message, files)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_porcelain_status() {
+        let output = " M flake.nix\0?? flake-parts/new.nix\0R  renamed.nix\0original.nix\0";
+
+        assert_eq!(
+            parse_porcelain_status(output),
+            vec![
+                PathBuf::from("flake.nix"),
+                PathBuf::from("flake-parts/new.nix"),
+                PathBuf::from("renamed.nix"),
+                PathBuf::from("original.nix"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ensure_clean() -> Result<()> {
+        let Ok(git) = GitExecutor::from_env() else {
+            return Ok(());
+        };
+
+        let repo = TempDir::new()?;
+        let root = repo.path().canonicalize()?;
+        let mut command = git.git_command(&root);
+        command.arg("init");
+        git.run(command)?;
+
+        fs::write(root.join("dirty.txt"), "uncommitted")?;
+
+        ensure_clean(&root, [Path::new("clean.txt")])?;
+        assert!(ensure_clean(&root, [Path::new("clean.txt"), Path::new("dirty.txt")]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ensure_clean_outside_of_repository() -> Result<()> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("file.txt"), "contents")?;
+
+        // NOTE tmpdirs usually aren't inside of a git repository, but
+        // better be safe than sorry
+        if let Ok(git) = GitExecutor::from_env() {
+            if git.toplevel(dir.path())?.is_some() {
+                return Ok(());
+            }
+        }
+
+        ensure_clean(dir.path(), [Path::new("file.txt")])?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_track_files() -> Result<()> {
+        let Ok(git) = GitExecutor::from_env() else {
+            return Ok(());
+        };
+
+        let dir = TempDir::new()?;
+        let root = dir.path().canonicalize()?;
+        fs::write(root.join("flake.nix"), "{ }")?;
+        fs::write(root.join("untouched.txt"), "contents")?;
+
+        track_files(&root, &[PathBuf::from("flake.nix")], false, None)?;
+
+        let toplevel = git.toplevel(&root)?;
+        assert_eq!(toplevel, Some(root.clone()));
+
+        let mut command = git.git_command(&root);
+        command.args(["status", "--porcelain"]);
+        assert_eq!(git.run(command)?, "A  flake.nix\n?? untouched.txt\n");
+        Ok(())
+    }
+}
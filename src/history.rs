@@ -0,0 +1,468 @@
+//! Per-project journal of applied operations. Every operation saves the
+//! previous versions of the files it touches, which allows to revert it
+//! later on using the `undo` command.
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+use crate::apply::{apply_changes, ChangeKind, FileChange};
+use crate::config::{HISTORY_DIR, STATE_DIR};
+use crate::fs_utils::{copy_entry, read_entry};
+
+static ENTRY_FILE: &str = "entry.json";
+static BACKUP_DIR: &str = "backup";
+static APPLIED_DIR: &str = "applied";
+
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    #[error("There are no recorded operations to undo in {0:?}")]
+    EmptyHistoryError(PathBuf),
+
+    #[error(
+        "Operation {0} wasn't found in the history, use `undo --list` to show the available ones"
+    )]
+    UnknownEntryError(u32),
+
+    #[error("Files {1:?} were modified after operation {0}, undoing it would discard the modifications, use `--force` to undo it anyway")]
+    ModifiedFilesError(u32, Vec<PathBuf>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalFile {
+    /// Path relative to the project root
+    pub path: PathBuf,
+
+    /// Whether the file existed before the operation, in which case its
+    /// previous version is saved in the backup directory of the entry
+    pub existed: bool,
+
+    /// Whether the operation removed the file, otherwise the written
+    /// version is saved in the applied directory of the entry
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: u32,
+
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+
+    pub command: String,
+    pub files: Vec<JournalFile>,
+
+    /// Directories created by the operation, relative to the project root
+    #[serde(default)]
+    pub created_dirs: Vec<PathBuf>,
+}
+
+impl JournalEntry {
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.timestamp)
+    }
+}
+
+pub struct History {
+    root: PathBuf,
+    dir: PathBuf,
+}
+
+impl History {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            dir: root.join(STATE_DIR).join(HISTORY_DIR),
+        }
+    }
+
+    fn entry_dir(&self, id: u32) -> PathBuf {
+        self.dir.join(format!("{:04}", id))
+    }
+
+    /// Returns all of the recorded entries sorted from the oldest one.
+    pub fn entries(&self) -> Result<Vec<JournalEntry>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::new();
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let entry_file = dir_entry?.path().join(ENTRY_FILE);
+
+            // NOTE the entry file is written last, so its absence means
+            // that the recording itself was interrupted
+            if !entry_file.exists() {
+                log::warn!("Skipping incomplete history entry {:?}", entry_file);
+                continue;
+            }
+
+            entries.push(serde_json::from_str::<JournalEntry>(&fs::read_to_string(
+                entry_file,
+            )?)?);
+        }
+        entries.sort_by_key(|entry| entry.id);
+
+        Ok(entries)
+    }
+
+    /// Saves the current versions of all files that will be touched by
+    /// `changes`, together with the versions `changes` write, into a new
+    /// entry.
+    pub fn record(&self, command: &str, changes: &[FileChange]) -> Result<JournalEntry> {
+        let id = self.entries()?.last().map_or(1, |entry| entry.id + 1);
+        let entry_dir = self.entry_dir(id);
+        log::info!("Recording operation {} to {:?}", id, entry_dir);
+
+        let mut files = Vec::new();
+        let mut created_dirs = Vec::new();
+        for change in changes {
+            let existed = change.kind != ChangeKind::Create;
+            if existed {
+                let backup = entry_dir.join(BACKUP_DIR).join(&change.path);
+                if let Some(parent) = backup.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                copy_entry(&self.root.join(&change.path), &backup)?;
+            }
+
+            let deleted = change.kind == ChangeKind::Delete;
+            if !deleted {
+                let applied = entry_dir.join(APPLIED_DIR).join(&change.path);
+                if let Some(parent) = applied.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(applied, &change.contents)?;
+            }
+
+            for dir in change.path.ancestors().skip(1) {
+                if dir.as_os_str().is_empty() || self.root.join(dir).exists() {
+                    break;
+                }
+                if !created_dirs.iter().any(|created| created == dir) {
+                    created_dirs.push(dir.to_path_buf());
+                }
+            }
+
+            files.push(JournalFile {
+                path: change.path.clone(),
+                existed,
+                deleted,
+            });
+        }
+
+        let entry = JournalEntry {
+            id,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            command: command.to_string(),
+            files,
+            created_dirs,
+        };
+
+        fs::create_dir_all(&entry_dir)?;
+        fs::write(
+            entry_dir.join(ENTRY_FILE),
+            serde_json::to_string_pretty(&entry)?,
+        )?;
+
+        Ok(entry)
+    }
+
+    fn remove_entry(&self, id: u32) -> Result<()> {
+        fs::remove_dir_all(self.entry_dir(id))?;
+        Ok(())
+    }
+
+    /// Plans the changes restoring the state from before all of the
+    /// `entries` (ordered from the most recent one), that is, the backed up
+    /// versions of modified files and removal of created ones. Fails if a
+    /// file was modified after the entry that wrote it, unless `force`.
+    fn revert_changes(&self, entries: &[JournalEntry], force: bool) -> Result<Vec<FileChange>> {
+        // NOTE states of the files after reverting the already processed
+        // entries, `None` stands for a removed file
+        let mut states: BTreeMap<PathBuf, Option<PathBuf>> = BTreeMap::new();
+
+        for entry in entries {
+            let entry_dir = self.entry_dir(entry.id);
+            let mut modified = Vec::new();
+
+            for file in &entry.files {
+                let current = match states.get(&file.path) {
+                    Some(state) => state.clone(),
+                    None => Some(self.root.join(&file.path)),
+                };
+                let current = match current {
+                    Some(path) => read_state(&path)?,
+                    None => None,
+                };
+                let applied = match file.deleted {
+                    true => None,
+                    false => read_state(&entry_dir.join(APPLIED_DIR).join(&file.path))?,
+                };
+                if current != applied {
+                    modified.push(file.path.clone());
+                }
+
+                let previous = file
+                    .existed
+                    .then(|| entry_dir.join(BACKUP_DIR).join(&file.path));
+                states.insert(file.path.clone(), previous);
+            }
+
+            if !modified.is_empty() {
+                if !force {
+                    return Err(HistoryError::ModifiedFilesError(entry.id, modified).into());
+                }
+                log::warn!(
+                    "Discarding modifications of {:?} made after operation {}",
+                    modified,
+                    entry.id
+                );
+            }
+        }
+
+        let mut changes = Vec::new();
+        for (path, state) in states {
+            let exists = self.root.join(&path).symlink_metadata().is_ok();
+            match (state, exists) {
+                (Some(backup), true) => {
+                    changes.push(FileChange::from_entry(path, ChangeKind::Modify, &backup)?)
+                }
+                (Some(backup), false) => {
+                    changes.push(FileChange::from_entry(path, ChangeKind::Create, &backup)?)
+                }
+                (None, true) => changes.push(FileChange::new(path, ChangeKind::Delete, Vec::new())),
+                (None, false) => {}
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Reverts the most recent operation, or all operations up to and
+    /// including the one with id `to`, in a single transaction. Returns the
+    /// reverted entries.
+    pub fn undo(&self, to: Option<u32>, force: bool) -> Result<Vec<JournalEntry>> {
+        let entries = self.entries()?;
+
+        let to = match (to, entries.last()) {
+            (_, None) => Err(HistoryError::EmptyHistoryError(self.dir.clone()))?,
+            (None, Some(last)) => last.id,
+            (Some(id), _) if entries.iter().any(|entry| entry.id == id) => id,
+            (Some(id), _) => Err(HistoryError::UnknownEntryError(id))?,
+        };
+
+        let reverted = entries
+            .into_iter()
+            .rev()
+            .take_while(|entry| entry.id >= to)
+            .collect::<Vec<_>>();
+
+        for entry in &reverted {
+            log::info!("Reverting operation {} `{}`", entry.id, entry.command);
+        }
+        let changes = self.revert_changes(&reverted, force)?;
+        apply_changes(&self.root, &changes)?;
+
+        // NOTE only the directories created by the operations are removed,
+        // and only if they're empty
+        let mut created_dirs = reverted
+            .iter()
+            .flat_map(|entry| entry.created_dirs.iter())
+            .collect::<Vec<_>>();
+        created_dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+        for dir in created_dirs {
+            let _ = fs::remove_dir(self.root.join(dir));
+        }
+
+        for entry in &reverted {
+            self.remove_entry(entry.id)?;
+        }
+
+        Ok(reverted)
+    }
+}
+
+/// Contents of the file at `path` (or the target of a symlink), `None` if
+/// there's no such file.
+fn read_state(path: &Path) -> Result<Option<Vec<u8>>> {
+    match path.symlink_metadata() {
+        Ok(_) => Ok(Some(read_entry(path)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Records the `changes` into the project history and applies them. If
+/// the application fails, the recorded entry is discarded again.
+pub fn apply_recorded(root: &Path, command: &str, changes: &[FileChange]) -> Result<()> {
+    if changes.is_empty() {
+        log::info!("No changes to apply, nothing will be recorded");
+        return Ok(());
+    }
+
+    let history = History::new(root);
+    let entry = history.record(command, changes)?;
+
+    if let Err(err) = apply_changes(root, changes) {
+        history.remove_entry(entry.id)?;
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+/// Human readable description of the currently executed command.
+pub fn current_command() -> String {
+    std::env::args().skip(1).collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn change(path: &str, kind: ChangeKind, contents: &str) -> FileChange {
+        FileChange::new(PathBuf::from(path), kind, contents.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_apply_recorded_and_undo() -> Result<()> {
+        let root = TempDir::new()?;
+        fs::write(root.path().join("existing.txt"), "old")?;
+
+        apply_recorded(
+            root.path(),
+            "init",
+            &[
+                change("existing.txt", ChangeKind::Modify, "new"),
+                change("nested/created.txt", ChangeKind::Create, "created"),
+            ],
+        )?;
+        assert_eq!(fs::read_to_string(root.path().join("existing.txt"))?, "new");
+
+        let history = History::new(root.path());
+        let reverted = history.undo(None, false)?;
+
+        assert_eq!(reverted.len(), 1);
+        assert_eq!(fs::read_to_string(root.path().join("existing.txt"))?, "old");
+        assert!(!root.path().join("nested").exists());
+        assert!(history.entries()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_to() -> Result<()> {
+        let root = TempDir::new()?;
+        fs::write(root.path().join("file.txt"), "v1")?;
+
+        for (i, contents) in ["v2", "v3", "v4"].iter().enumerate() {
+            apply_recorded(
+                root.path(),
+                &format!("add {}", i),
+                &[change("file.txt", ChangeKind::Modify, contents)],
+            )?;
+        }
+
+        let history = History::new(root.path());
+        assert_eq!(
+            history
+                .entries()?
+                .iter()
+                .map(|entry| entry.id)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+
+        let reverted = history.undo(Some(2), false)?;
+        assert_eq!(
+            reverted.iter().map(|entry| entry.id).collect::<Vec<_>>(),
+            vec![3, 2]
+        );
+        assert_eq!(fs::read_to_string(root.path().join("file.txt"))?, "v2");
+        assert_eq!(history.entries()?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_errors() -> Result<()> {
+        let root = TempDir::new()?;
+        let history = History::new(root.path());
+        assert!(history.undo(None, false).is_err());
+
+        apply_recorded(
+            root.path(),
+            "init",
+            &[change("file.txt", ChangeKind::Create, "contents")],
+        )?;
+        assert!(history.undo(Some(42), false).is_err());
+        assert!(root.path().join("file.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_modified_files() -> Result<()> {
+        let root = TempDir::new()?;
+        fs::write(root.path().join("file.txt"), "v1")?;
+
+        for contents in ["v2", "v3"] {
+            apply_recorded(
+                root.path(),
+                "add",
+                &[change("file.txt", ChangeKind::Modify, contents)],
+            )?;
+        }
+        fs::write(root.path().join("file.txt"), "edited")?;
+
+        // NOTE nothing is reverted, not even the entries before the edit
+        let history = History::new(root.path());
+        assert!(history.undo(Some(1), false).is_err());
+        assert_eq!(fs::read_to_string(root.path().join("file.txt"))?, "edited");
+        assert_eq!(history.entries()?.len(), 2);
+
+        history.undo(Some(1), true)?;
+        assert_eq!(fs::read_to_string(root.path().join("file.txt"))?, "v1");
+        assert!(history.entries()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_keeps_existing_dirs() -> Result<()> {
+        let root = TempDir::new()?;
+        fs::create_dir(root.path().join("empty"))?;
+
+        apply_recorded(
+            root.path(),
+            "init",
+            &[
+                change("empty/file.txt", ChangeKind::Create, "contents"),
+                change("new/nested/file.txt", ChangeKind::Create, "contents"),
+            ],
+        )?;
+
+        History::new(root.path()).undo(None, false)?;
+        assert!(root.path().join("empty").is_dir());
+        assert!(!root.path().join("empty/file.txt").exists());
+        assert!(!root.path().join("new").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_recorded_failure() -> Result<()> {
+        let root = TempDir::new()?;
+        fs::write(root.path().join("blocker"), "not a directory")?;
+
+        let result = apply_recorded(
+            root.path(),
+            "init",
+            &[change("blocker/file.txt", ChangeKind::Create, "contents")],
+        );
+
+        assert!(result.is_err());
+        assert!(History::new(root.path()).entries()?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! Post-apply hooks declared by parts in their `meta.nix`, these are run
+//! in the target directory after all files were written.
+use color_eyre::eyre::Result;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::process::Command;
+
+use crate::parts::{FlakePartHook, FlakePartTuple};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingHook {
+    /// Flake uri of the part declaring the hook
+    pub part: String,
+    pub hook: FlakePartHook,
+}
+
+/// Collects the hooks of all `parts_tuples`, commands declared by multiple
+/// parts are run only once.
+pub fn collect_hooks(parts_tuples: &[FlakePartTuple]) -> Vec<PendingHook> {
+    let mut hooks: Vec<PendingHook> = Vec::new();
+
+    for part_tuple in parts_tuples {
+        for hook in &part_tuple.part.metadata.hooks {
+            if hooks
+                .iter()
+                .any(|pending| pending.hook.command == hook.command)
+            {
+                continue;
+            }
+
+            hooks.push(PendingHook {
+                part: part_tuple.to_flake_uri(None),
+                hook: hook.clone(),
+            });
+        }
+    }
+
+    hooks
+}
+
+/// Lists the `hooks` and asks the user whether they should be run.
+pub fn confirm_hooks<R: BufRead, W: Write>(
+    hooks: &[PendingHook],
+    mut input: R,
+    mut output: W,
+) -> Result<bool> {
+    writeln!(output, "The applied parts declare the following hooks:")?;
+    for pending in hooks {
+        writeln!(
+            output,
+            "  - `{}` ({}): {}",
+            pending.hook.command, pending.part, pending.hook.description
+        )?;
+    }
+    write!(output, "Run them now? [y/N]: ")?;
+    output.flush()?;
+
+    let mut answer = String::new();
+    // NOTE closed stdin is treated as a refusal
+    input.read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Runs all of the `hooks` in `dir` and reports their results to `output`.
+/// Failing hooks don't stop the remaining ones, returns the number of
+/// failed hooks.
+pub fn run_hooks<W: Write>(hooks: &[PendingHook], dir: &Path, mut output: W) -> Result<usize> {
+    let mut failed = 0;
+
+    for pending in hooks {
+        log::info!("Running hook `{}` in {:?}", pending.hook.command, dir);
+
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(&pending.hook.command)
+            .current_dir(dir)
+            .status();
+
+        match status {
+            Ok(status) if status.success() => {
+                writeln!(output, "Hook `{}` succeeded", pending.hook.command)?;
+            }
+            Ok(status) => {
+                failed += 1;
+                writeln!(
+                    output,
+                    "Hook `{}` from {} failed with {}",
+                    pending.hook.command, pending.part, status
+                )?;
+            }
+            Err(err) => {
+                failed += 1;
+                writeln!(
+                    output,
+                    "Hook `{}` from {} couldn't be run: {}",
+                    pending.hook.command, pending.part, err
+                )?;
+            }
+        }
+    }
+
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    fn pending(command: &str) -> PendingHook {
+        PendingHook {
+            part: "store#flake-parts/part".to_string(),
+            hook: FlakePartHook {
+                description: "Test hook".to_string(),
+                command: command.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_confirm_hooks() -> Result<()> {
+        let hooks = vec![pending("direnv allow")];
+
+        let mut output = Vec::new();
+        assert!(confirm_hooks(&hooks, Cursor::new("y\n"), &mut output)?);
+        assert!(String::from_utf8(output)?
+            .contains("  - `direnv allow` (store#flake-parts/part): Test hook\n"));
+
+        assert!(!confirm_hooks(&hooks, Cursor::new("n\n"), Vec::new())?);
+        assert!(!confirm_hooks(&hooks, Cursor::new(""), Vec::new())?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_hooks() -> Result<()> {
+        let dir = TempDir::new()?;
+        let hooks = vec![pending("exit 1"), pending("touch created"), pending("true")];
+
+        let mut output = Vec::new();
+        let failed = run_hooks(&hooks, dir.path(), &mut output)?;
+
+        assert_eq!(failed, 1);
+        assert!(dir.path().join("created").exists());
+        assert!(
+            String::from_utf8(output)?.contains("Hook `exit 1` from store#flake-parts/part failed")
+        );
+        Ok(())
+    }
+}
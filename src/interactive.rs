@@ -0,0 +1,315 @@
+//! Interactive per-file resolution of files that already exist in the
+//! target directory and differ from the version provided by the parts.
+use color_eyre::eyre::Result;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::cmd::init::ExistingFlake;
+use crate::fs_utils::unified_diff;
+use crate::merge::merge_contents;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Resolution {
+    Kept,
+    TookPart,
+    Merged,
+    Edited,
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Self::Kept => "kept your version",
+            Self::TookPart => "took the part version",
+            Self::Merged => "merged",
+            Self::Edited => "edited manually",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+pub struct InteractiveResolver<R, W> {
+    input: R,
+    output: W,
+    pub summary: Vec<(PathBuf, Resolution)>,
+}
+
+impl<R: BufRead, W: Write> InteractiveResolver<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            summary: Vec::new(),
+        }
+    }
+
+    fn prompt(&mut self, path: &Path) -> Result<String> {
+        write!(
+            self.output,
+            "[k]eep / [t]ake part version / show [d]iff / [m]erge / open in [e]ditor ({}): ",
+            path.display()
+        )?;
+        self.output.flush()?;
+
+        let mut answer = String::new();
+        // NOTE closed stdin is treated as keeping the user's version
+        if self.input.read_line(&mut answer)? == 0 {
+            return Ok("k".to_string());
+        }
+        Ok(answer.trim().to_lowercase())
+    }
+
+    /// Asks the user how to resolve a single file, returns the new content
+    /// of the file or `None` if the file should be left untouched.
+    pub fn resolve(&mut self, path: &Path, ours: &[u8], theirs: &[u8]) -> Result<Option<Vec<u8>>> {
+        writeln!(
+            self.output,
+            "File `{}` already exists and differs from the part version.",
+            path.display()
+        )?;
+
+        let texts = match (std::str::from_utf8(ours), std::str::from_utf8(theirs)) {
+            (Ok(ours), Ok(theirs)) => Some((ours, theirs)),
+            _ => None,
+        };
+
+        loop {
+            let (resolution, contents) = match (self.prompt(path)?.as_str(), texts) {
+                ("k" | "keep", _) => (Resolution::Kept, None),
+                ("t" | "take", _) => (Resolution::TookPart, Some(theirs.to_vec())),
+                ("d" | "diff", Some((ours, theirs))) => {
+                    writeln!(self.output, "--- {} (yours)", path.display())?;
+                    writeln!(self.output, "+++ {} (part)", path.display())?;
+                    write!(self.output, "{}", unified_diff(ours, theirs, 3))?;
+                    continue;
+                }
+                ("m" | "merge", Some((ours, theirs))) => match merge_contents(path, ours, theirs) {
+                    Some(merged) => (Resolution::Merged, Some(merged.into_bytes())),
+                    None => {
+                        writeln!(self.output, "This file can't be merged automatically.")?;
+                        continue;
+                    }
+                },
+                ("e" | "editor", Some((ours, theirs))) => {
+                    let initial = merge_contents(path, ours, theirs)
+                        .unwrap_or_else(|| conflict_markers(ours, theirs));
+                    (Resolution::Edited, Some(edit_in_editor(path, &initial)?))
+                }
+                ("d" | "diff" | "m" | "merge" | "e" | "editor", None) => {
+                    writeln!(self.output, "This option isn't available for binary files.")?;
+                    continue;
+                }
+                _ => {
+                    writeln!(self.output, "Unknown option, please try again.")?;
+                    continue;
+                }
+            };
+
+            self.summary.push((path.to_path_buf(), resolution));
+            return Ok(contents);
+        }
+    }
+
+    pub fn print_summary(&mut self) -> Result<()> {
+        if self.summary.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(self.output, "Summary of resolved files:")?;
+        for (path, resolution) in &self.summary {
+            writeln!(self.output, "  - {}: {}", path.display(), resolution)?;
+        }
+        Ok(())
+    }
+}
+
+/// Asks the user what to do with an already existing `flake.nix` in `path`.
+pub fn ask_existing_flake<R: BufRead, W: Write>(
+    path: &Path,
+    mut input: R,
+    mut output: W,
+) -> Result<ExistingFlake> {
+    writeln!(output, "{} already contains a flake.nix.", path.display())?;
+
+    loop {
+        write!(
+            output,
+            "[a]dd new inputs to it / [o]verwrite it / [c]ancel: "
+        )?;
+        output.flush()?;
+
+        let mut answer = String::new();
+        // NOTE closed stdin is treated as cancelling
+        if input.read_line(&mut answer)? == 0 {
+            return Ok(ExistingFlake::Refuse);
+        }
+
+        match answer.trim().to_lowercase().as_str() {
+            "a" | "add" => return Ok(ExistingFlake::Add),
+            "o" | "overwrite" => return Ok(ExistingFlake::Overwrite),
+            "c" | "cancel" => return Ok(ExistingFlake::Refuse),
+            _ => writeln!(output, "Unknown option, please try again.")?,
+        }
+    }
+}
+
+/// Wraps both versions in conflict markers, each marker starts on a new
+/// line even if the preceding version doesn't end with a newline.
+fn conflict_markers(ours: &str, theirs: &str) -> String {
+    let newline = |text: &str| {
+        if text.is_empty() || text.ends_with('\n') {
+            ""
+        } else {
+            "\n"
+        }
+    };
+    format!(
+        "<<<<<<< ours\n{}{}=======\n{}{}>>>>>>> theirs\n",
+        ours,
+        newline(ours),
+        theirs,
+        newline(theirs)
+    )
+}
+
+/// Opens `contents` in `$VISUAL` or `$EDITOR` (defaulting to `vi`) and
+/// returns the edited result.
+fn edit_in_editor(path: &Path, contents: &str) -> Result<Vec<u8>> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+
+    let extension = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    let file = tempfile::Builder::new().suffix(&extension).tempfile()?;
+    fs::write(file.path(), contents)?;
+
+    // NOTE running through the shell allows editors with arguments, eg. `code -w`
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(file.path())
+        .status()?;
+
+    if !status.success() {
+        Err(std::io::Error::other(format!(
+            "Editor `{}` exited with {}",
+            editor, status
+        )))?
+    }
+
+    Ok(fs::read(file.path())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn resolver(input: &str) -> InteractiveResolver<Cursor<Vec<u8>>, Vec<u8>> {
+        InteractiveResolver::new(Cursor::new(input.as_bytes().to_vec()), Vec::new())
+    }
+
+    #[test]
+    fn test_resolve_keep() -> Result<()> {
+        let mut resolver = resolver("k\n");
+        let result = resolver.resolve(Path::new("a.txt"), b"ours", b"theirs")?;
+
+        assert_eq!(result, None);
+        assert_eq!(
+            resolver.summary,
+            vec![(PathBuf::from("a.txt"), Resolution::Kept)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_take_after_diff() -> Result<()> {
+        let mut resolver = resolver("d\nt\n");
+        let result = resolver.resolve(Path::new("a.txt"), b"ours\n", b"theirs\n")?;
+
+        assert_eq!(result, Some(b"theirs\n".to_vec()));
+        let output = String::from_utf8(resolver.output.clone())?;
+        assert!(output.contains("-ours\n+theirs\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_merge() -> Result<()> {
+        let mut resolver = resolver("m\n");
+        let result = resolver.resolve(Path::new(".gitignore"), b"result\n", b".direnv\n")?;
+
+        assert_eq!(result, Some(b"result\n\n.direnv\n".to_vec()));
+        assert_eq!(
+            resolver.summary,
+            vec![(PathBuf::from(".gitignore"), Resolution::Merged)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_merge_unavailable() -> Result<()> {
+        let mut resolver = resolver("m\nx\nk\n");
+        let result = resolver.resolve(Path::new("README.md"), b"ours", b"theirs")?;
+
+        assert_eq!(result, None);
+        let output = String::from_utf8(resolver.output.clone())?;
+        assert!(output.contains("can't be merged automatically"));
+        assert!(output.contains("Unknown option"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_closed_input() -> Result<()> {
+        let mut resolver = resolver("");
+        let result = resolver.resolve(Path::new("a.txt"), b"ours", b"theirs")?;
+
+        assert_eq!(result, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_print_summary() -> Result<()> {
+        let mut resolver = resolver("k\nt\n");
+        resolver.resolve(Path::new("a.txt"), b"ours", b"theirs")?;
+        resolver.resolve(Path::new("b.txt"), b"ours", b"theirs")?;
+        resolver.print_summary()?;
+
+        let output = String::from_utf8(resolver.output.clone())?;
+        assert!(output.ends_with(
+            "Summary of resolved files:\n  - a.txt: kept your version\n  - b.txt: took the part version\n"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_markers() {
+        assert_eq!(
+            conflict_markers("a\n", "b"),
+            "<<<<<<< ours\na\n=======\nb\n>>>>>>> theirs\n"
+        );
+        assert_eq!(
+            conflict_markers("", "b\n"),
+            "<<<<<<< ours\n=======\nb\n>>>>>>> theirs\n"
+        );
+    }
+
+    #[test]
+    fn test_ask_existing_flake() -> Result<()> {
+        let path = Path::new("project");
+        let ask =
+            |input: &str| ask_existing_flake(path, Cursor::new(input.to_string()), Vec::new());
+
+        assert_eq!(ask("x\na\n")?, ExistingFlake::Add);
+        assert_eq!(ask("overwrite\n")?, ExistingFlake::Overwrite);
+        assert_eq!(ask("c\n")?, ExistingFlake::Refuse);
+        assert_eq!(ask("")?, ExistingFlake::Refuse);
+        Ok(())
+    }
+}
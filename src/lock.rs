@@ -0,0 +1,217 @@
+//! Record of the parts selection a project was created with, stored in
+//! `.flake-parts-builder/lock.json`. This allows to reproduce the same
+//! selection in another project using `init --from`.
+use color_eyre::eyre::Result;
+use glob::{Pattern, PatternError};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::apply::{ChangeKind, FileChange};
+use crate::config::{LOCK_FILE, STATE_DIR};
+use crate::fs_utils::compile_globs;
+use crate::nix::NixCmdInterface;
+
+#[derive(Error, Debug)]
+pub enum LockError {
+    #[error("No lock file was found in the project at {0:?}, it was most likely created by an older version of the builder")]
+    MissingLockError(PathBuf),
+
+    #[error("Failed to parse the lock file {0:?}: {1}")]
+    LockParseError(PathBuf, serde_json::Error),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProjectLock {
+    /// Additional parts stores passed using `-I`
+    #[serde(default)]
+    pub stores: Vec<String>,
+
+    #[serde(default)]
+    pub disable_base: bool,
+
+    /// Flake uris of all of the applied parts, including dependencies
+    #[serde(default)]
+    pub parts: Vec<String>,
+
+    /// Url of the `nixpkgs` input, unknown for projects with a lock
+    /// created by `add`
+    #[serde(default)]
+    pub nixpkgs: Option<String>,
+
+    /// Globs of files excluded from the parts, relative to the project root
+    #[serde(default)]
+    pub exclude_files: Vec<String>,
+}
+
+impl ProjectLock {
+    /// Path of the lock file relative to the project root.
+    pub fn relative_path() -> PathBuf {
+        Path::new(STATE_DIR).join(LOCK_FILE)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)
+            .map_err(|err| LockError::LockParseError(path.to_path_buf(), err))?)
+    }
+
+    /// Reads the lock of the project at `root`, if there's any.
+    pub fn read(root: &Path) -> Result<Option<Self>> {
+        let path = root.join(Self::relative_path());
+        if !path.exists() {
+            return Ok(None);
+        }
+        Self::from_file(&path).map(Some)
+    }
+
+    /// Adds the `stores` and `parts` that aren't recorded yet.
+    pub fn extend(&mut self, stores: &[String], parts: &[String]) {
+        for store in stores {
+            if !self.stores.contains(store) {
+                self.stores.push(store.clone());
+            }
+        }
+        for part in parts {
+            if !self.parts.contains(part) {
+                self.parts.push(part.clone());
+            }
+        }
+    }
+
+    /// Adds the `exclude_files` globs that aren't recorded yet.
+    pub fn extend_exclude_files(&mut self, exclude_files: &[String]) {
+        for glob in exclude_files {
+            if !self.exclude_files.contains(glob) {
+                self.exclude_files.push(glob.clone());
+            }
+        }
+    }
+
+    pub fn exclude_patterns(&self) -> Result<Vec<Pattern>, PatternError> {
+        compile_globs(&self.exclude_files)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)? + "\n")
+    }
+
+    /// Plans writing of the lock into the project at `root`, returns
+    /// `None` if the lock file is already up to date.
+    pub fn change(&self, root: &Path) -> Result<Option<FileChange>> {
+        let path = Self::relative_path();
+        let contents = self.to_json()?;
+
+        let kind = match fs::read_to_string(root.join(&path)) {
+            Ok(current) if current == contents => return Ok(None),
+            Ok(_) => ChangeKind::Modify,
+            Err(_) => ChangeKind::Create,
+        };
+
+        Ok(Some(FileChange::new(path, kind, contents.into_bytes())))
+    }
+}
+
+/// Reads the lock from `source`, which is either a directory of another
+/// project, a path to its lock file or an `http(s)://` url of one.
+pub fn read_lock_source(source: &str, nix_cmd: &impl NixCmdInterface) -> Result<ProjectLock> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        log::info!("Fetching the lock file from {}", source);
+        let path = nix_cmd.fetch_url(source)?;
+        return ProjectLock::from_file(&path);
+    }
+
+    let path = Path::new(source);
+    if path.is_dir() {
+        return ProjectLock::read(path)?
+            .ok_or_else(|| LockError::MissingLockError(path.to_path_buf()).into());
+    }
+
+    ProjectLock::from_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn lock() -> ProjectLock {
+        ProjectLock {
+            stores: vec!["github:org/store#flake-parts".to_string()],
+            disable_base: false,
+            parts: vec!["github:org/store#flake-parts/treefmt".to_string()],
+            nixpkgs: Some("github:nixos/nixpkgs/nixos-24.05".to_string()),
+            exclude_files: vec!["README.md".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_lock_change() -> Result<()> {
+        let root = TempDir::new()?;
+
+        let change = lock().change(root.path())?.unwrap();
+        assert_eq!(change.kind, ChangeKind::Create);
+        assert_eq!(change.path, PathBuf::from(".flake-parts-builder/lock.json"));
+
+        fs::create_dir_all(root.path().join(STATE_DIR))?;
+        fs::write(root.path().join(&change.path), &change.contents)?;
+        assert_eq!(ProjectLock::read(root.path())?, Some(lock()));
+        assert!(lock().change(root.path())?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_extend() {
+        let mut lock = lock();
+        lock.extend(
+            &["github:org/store#flake-parts".to_string()],
+            &[
+                "github:org/store#flake-parts/treefmt".to_string(),
+                "github:org/store#flake-parts/shells".to_string(),
+            ],
+        );
+
+        assert_eq!(lock.stores.len(), 1);
+        assert_eq!(
+            lock.parts,
+            vec![
+                "github:org/store#flake-parts/treefmt".to_string(),
+                "github:org/store#flake-parts/shells".to_string(),
+            ]
+        );
+
+        lock.extend_exclude_files(&["README.md".to_string(), "docs/**".to_string()]);
+        assert_eq!(lock.exclude_files, vec!["README.md", "docs/**"]);
+    }
+
+    #[test]
+    fn test_read_lock_source() -> Result<()> {
+        let root = TempDir::new()?;
+        let nix_cmd = crate::nix::NixExecutor::new(PathBuf::new(), PathBuf::new());
+
+        assert!(read_lock_source(root.path().to_str().unwrap(), &nix_cmd).is_err());
+
+        fs::create_dir_all(root.path().join(STATE_DIR))?;
+        fs::write(
+            root.path().join(ProjectLock::relative_path()),
+            lock().to_json()?,
+        )?;
+
+        assert_eq!(
+            read_lock_source(root.path().to_str().unwrap(), &nix_cmd)?,
+            lock()
+        );
+        assert_eq!(
+            read_lock_source(
+                root.path()
+                    .join(ProjectLock::relative_path())
+                    .to_str()
+                    .unwrap(),
+                &nix_cmd
+            )?,
+            lock()
+        );
+        Ok(())
+    }
+}
@@ -5,17 +5,36 @@
 use clap::{Parser, Subcommand};
 use color_eyre::eyre::Result;
 
+pub mod apply;
+pub mod archive;
 pub mod cmd;
 pub mod config;
+pub mod doctor;
 pub mod fs_utils;
+pub mod git;
+pub mod history;
+pub mod hooks;
+pub mod interactive;
+pub mod lock;
+pub mod manifest;
+pub mod merge;
 pub mod nix;
 pub mod parts;
+pub mod presets;
+pub mod project;
 pub mod templates;
+pub mod user_config;
 
 use crate::cmd::add::{add, AddCommand};
+use crate::cmd::apply::{apply, ApplyCommand};
+use crate::cmd::diff::{diff, DiffCommand};
+use crate::cmd::doctor::{doctor, DoctorCommand};
 use crate::cmd::init::{init, InitCommand};
 use crate::cmd::list::{list, ListCommand};
+use crate::cmd::sync::{sync, SyncCommand};
+use crate::cmd::undo::{undo, UndoCommand};
 use crate::nix::NixExecutor;
+use crate::user_config::UserConfig;
 
 /// Nix flakes interactive template builder based on flake-parts written
 /// in Rust.
@@ -31,6 +50,11 @@ enum Commands {
     Init(InitCommand),
     List(ListCommand),
     Add(AddCommand),
+    Undo(UndoCommand),
+    Doctor(DoctorCommand),
+    Diff(DiffCommand),
+    Sync(SyncCommand),
+    Apply(ApplyCommand),
 }
 
 // TODO add logging
@@ -41,20 +65,54 @@ fn main() -> Result<()> {
     log::debug!("color-eyre installed and logger initialized");
 
     let cli = Cli::parse();
-    let nix_cmd = NixExecutor::from_env()?;
 
     match cli.command {
-        Commands::List(cmd) => {
+        Commands::List(mut cmd) => {
             log::info!("Executing list command");
-            list(cmd, nix_cmd)
+            let user_config = UserConfig::load()?;
+            user_config.apply_to_shared_args(&mut cmd.shared_args)?;
+            cmd.presets = user_config.presets.clone();
+            list(cmd, NixExecutor::from_env()?)
         }
-        Commands::Init(cmd) => {
+        Commands::Init(mut cmd) => {
             log::info!("Executing init command");
-            init(cmd, nix_cmd)
+            let user_config = UserConfig::load()?;
+            user_config.apply_to_init(&mut cmd)?;
+            init(cmd, NixExecutor::from_env()?)
         }
-        Commands::Add(cmd) => {
+        Commands::Add(mut cmd) => {
             log::info!("Executing add command");
-            add(cmd, nix_cmd)
+            let user_config = UserConfig::load()?;
+            user_config.apply_to_add(&mut cmd)?;
+            add(cmd, NixExecutor::from_env()?)
+        }
+        Commands::Undo(cmd) => {
+            log::info!("Executing undo command");
+            undo(cmd)
+        }
+        Commands::Doctor(mut cmd) => {
+            log::info!("Executing doctor command");
+            let user_config = UserConfig::load()?;
+            user_config.apply_to_shared_args(&mut cmd.shared_args)?;
+            doctor(cmd, NixExecutor::from_env()?)
+        }
+        Commands::Diff(mut cmd) => {
+            log::info!("Executing diff command");
+            let user_config = UserConfig::load()?;
+            user_config.apply_to_shared_args(&mut cmd.shared_args)?;
+            diff(cmd, NixExecutor::from_env()?)
+        }
+        Commands::Sync(mut cmd) => {
+            log::info!("Executing sync command");
+            let user_config = UserConfig::load()?;
+            user_config.apply_to_shared_args(&mut cmd.shared_args)?;
+            sync(cmd, NixExecutor::from_env()?)
+        }
+        Commands::Apply(mut cmd) => {
+            log::info!("Executing apply command");
+            let user_config = UserConfig::load()?;
+            cmd.user_config = user_config;
+            apply(cmd, NixExecutor::from_env()?)
         }
     }
 }
@@ -68,4 +126,27 @@ mod tests {
         use clap::CommandFactory;
         Cli::command().debug_assert();
     }
+
+    #[test]
+    fn test_base_flags_override_each_other() {
+        let Commands::List(cmd) =
+            Cli::parse_from(["flake-parts-builder", "list", "--enable-base"]).command
+        else {
+            unreachable!()
+        };
+        assert!(cmd.shared_args.enable_base_parts);
+
+        let Commands::List(cmd) = Cli::parse_from([
+            "flake-parts-builder",
+            "list",
+            "--disable-base",
+            "--enable-base",
+        ])
+        .command
+        else {
+            unreachable!()
+        };
+        assert!(cmd.shared_args.enable_base_parts);
+        assert!(!cmd.shared_args.disable_base_parts);
+    }
 }
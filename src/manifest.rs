@@ -0,0 +1,157 @@
+//! Declarative project manifest (`flake-parts.toml` or `flake-parts.nix`)
+//! describing the desired parts selection, the project is reconciled with
+//! it using the `apply` command.
+use color_eyre::eyre::Result;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::cmd::init::{ExistingFlake, InitStrategy};
+use crate::config::MANIFEST_FILES;
+use crate::nix::NixCmdInterface;
+use crate::presets::Preset;
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error("No manifest was found in {0:?}, please create a `flake-parts.toml` or `flake-parts.nix` file or pass its path using `--manifest`")]
+    NoManifestError(PathBuf),
+
+    #[error("Unsupported manifest format of {0:?}, please use either a `.toml` or a `.nix` file")]
+    UnknownFormatError(PathBuf),
+
+    #[error("Failed to parse the manifest {0:?}: {1}")]
+    ManifestParseError(PathBuf, String),
+}
+
+/// Desired state of a project, the fields mirror the options of the `init`
+/// command.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Manifest {
+    /// Additional parts stores, same as `-I`
+    #[serde(default)]
+    pub stores: Vec<String>,
+
+    #[serde(default)]
+    pub disable_base: bool,
+
+    #[serde(default)]
+    pub parts: Vec<String>,
+
+    /// Strategy used for already existing files, `skip` by default
+    pub strategy: Option<InitStrategy>,
+
+    /// How to bootstrap a directory that already contains a `flake.nix`
+    pub existing_flake: Option<ExistingFlake>,
+
+    /// Url of the `nixpkgs` input of the generated `flake.nix`
+    pub nixpkgs: Option<String>,
+
+    #[serde(default)]
+    pub ignore_conflicts: bool,
+
+    #[serde(default)]
+    pub ignore_unresolved_deps: bool,
+
+    /// Files of the parts that shouldn't be added, see `--exclude-files`
+    #[serde(default)]
+    pub exclude_files: Vec<String>,
+
+    /// Presets usable in `parts` as `@name`
+    #[serde(default)]
+    pub presets: BTreeMap<String, Preset>,
+}
+
+impl Manifest {
+    /// Finds the manifest in `dir`, `flake-parts.toml` takes precedence.
+    pub fn find(dir: &Path) -> Option<PathBuf> {
+        MANIFEST_FILES
+            .iter()
+            .map(|file| dir.join(file))
+            .find(|path| path.is_file())
+    }
+
+    pub fn from_toml(path: &Path, contents: &str) -> Result<Self, ManifestError> {
+        toml::from_str(contents)
+            .map_err(|err| ManifestError::ManifestParseError(path.to_path_buf(), err.to_string()))
+    }
+
+    /// Reads the manifest at `path`, `.nix` manifests are evaluated to JSON
+    /// using `nix_cmd` first.
+    pub fn from_file(path: &Path, nix_cmd: &impl NixCmdInterface) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Ok(Self::from_toml(path, &fs::read_to_string(path)?)?),
+            Some("nix") => {
+                let eval_output = nix_cmd.eval_nix_file(path, true)?;
+                Ok(serde_json::from_str(&eval_output).map_err(|err| {
+                    ManifestError::ManifestParseError(path.to_path_buf(), err.to_string())
+                })?)
+            }
+            _ => Err(ManifestError::UnknownFormatError(path.to_path_buf()))?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_manifest_from_toml() -> Result<(), ManifestError> {
+        let contents = r#"
+stores = ["github:org/store#flake-parts"]
+parts = ["treefmt", "shells"]
+strategy = "merge"
+existing-flake = "add"
+nixpkgs = "github:nixos/nixpkgs/nixos-24.05"
+ignore-conflicts = true
+"#;
+
+        assert_eq!(
+            Manifest::from_toml(Path::new("flake-parts.toml"), contents)?,
+            Manifest {
+                stores: vec!["github:org/store#flake-parts".to_string()],
+                disable_base: false,
+                parts: vec!["treefmt".to_string(), "shells".to_string()],
+                strategy: Some(InitStrategy::Merge),
+                existing_flake: Some(ExistingFlake::Add),
+                nixpkgs: Some("github:nixos/nixpkgs/nixos-24.05".to_string()),
+                ignore_conflicts: true,
+                ignore_unresolved_deps: false,
+                exclude_files: Vec::new(),
+                presets: BTreeMap::new(),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_from_toml_errors() {
+        let path = Path::new("flake-parts.toml");
+
+        assert!(Manifest::from_toml(path, "strategy = \"unknown\"").is_err());
+        assert!(Manifest::from_toml(path, "typo = true").is_err());
+    }
+
+    #[test]
+    fn test_find_manifest() -> std::io::Result<()> {
+        let dir = TempDir::new()?;
+        assert_eq!(Manifest::find(dir.path()), None);
+
+        fs::write(dir.path().join("flake-parts.nix"), "{ }")?;
+        assert_eq!(
+            Manifest::find(dir.path()),
+            Some(dir.path().join("flake-parts.nix"))
+        );
+
+        fs::write(dir.path().join("flake-parts.toml"), "")?;
+        assert_eq!(
+            Manifest::find(dir.path()),
+            Some(dir.path().join("flake-parts.toml"))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+//! Built-in merge handlers used by the `merge` strategy for files that are
+//! contributed by multiple parts or that already exist in the target directory.
+use std::path::Path;
+use thiserror::Error;
+
+pub mod nix;
+pub mod text;
+pub mod yaml;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MergeHandler {
+    /// Line-set union of ignore patterns (`.gitignore`, `.dockerignore`, ...)
+    IgnoreFile,
+
+    /// Deduplicated append of shell snippets, used for `.envrc`
+    Envrc,
+
+    /// Structural merge of YAML documents by keys, used for CI workflows
+    Yaml,
+
+    /// Syntax tree aware merge of Nix attribute sets by attribute paths
+    Nix,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeOutcome {
+    pub content: String,
+
+    /// Paths of values that differ on both sides, these are either kept
+    /// as they are (YAML) or marked with conflict markers (Nix)
+    pub conflicts: Vec<String>,
+
+    /// Paths of values that weren't added as that would require
+    /// reformatting the existing file (YAML)
+    pub skipped: Vec<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum MergeError {
+    #[error("failed to parse YAML document: {0}")]
+    YamlParseError(#[from] serde_yaml::Error),
+
+    #[error("failed to parse nix expression: {0}")]
+    NixParseError(#[from] rnix::parser::ParseError),
+
+    #[error("nix expressions have incompatible structure, only attribute sets can be merged")]
+    NixStructureError,
+
+    #[error(
+        "nix expressions have overlapping changes, an attribute is likely defined multiple times"
+    )]
+    NixOverlapError,
+}
+
+impl MergeHandler {
+    pub fn for_path(path: &Path) -> Option<Self> {
+        let file_name = path.file_name()?.to_str()?;
+
+        match file_name {
+            ".envrc" => Some(Self::Envrc),
+            name if name.starts_with('.') && name.ends_with("ignore") => Some(Self::IgnoreFile),
+            name if name.ends_with(".yml") || name.ends_with(".yaml") => Some(Self::Yaml),
+            name if name.ends_with(".nix") => Some(Self::Nix),
+            _ => None,
+        }
+    }
+
+    /// Merges `theirs` (the part version) into `ours` (the already present
+    /// version). Merging is idempotent, that is, merging the same `theirs`
+    /// into the result again leaves it unchanged.
+    pub fn merge(&self, ours: &str, theirs: &str) -> Result<MergeOutcome, MergeError> {
+        let content = match self {
+            Self::IgnoreFile => text::merge_ignore_file(ours, theirs),
+            Self::Envrc => text::merge_envrc(ours, theirs),
+            Self::Yaml => return yaml::merge_yaml(ours, theirs),
+            Self::Nix => return nix::merge_nix(ours, theirs),
+        };
+
+        Ok(MergeOutcome {
+            content,
+            conflicts: Vec::new(),
+            skipped: Vec::new(),
+        })
+    }
+}
+
+/// Merges `theirs` into `ours` using the handler registered for the file
+/// name of `path`. Returns `None` if there's no such handler or if the
+/// contents couldn't be merged.
+pub fn merge_contents(path: &Path, ours: &str, theirs: &str) -> Option<String> {
+    let Some(handler) = MergeHandler::for_path(path) else {
+        log::warn!("No merge handler available for {:?}", path);
+        return None;
+    };
+
+    let outcome = match handler.merge(ours, theirs) {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            log::warn!("Failed to merge {:?}: {}", path, err);
+            return None;
+        }
+    };
+
+    for conflict in &outcome.conflicts {
+        match handler {
+            MergeHandler::Nix => log::warn!(
+                "Conflicting value of `{}` in {:?}, please resolve the conflict markers",
+                conflict,
+                path
+            ),
+            _ => log::warn!(
+                "Conflicting value of `{}` in {:?}, keeping the existing one",
+                conflict,
+                path
+            ),
+        }
+    }
+
+    for skipped in &outcome.skipped {
+        log::warn!(
+            "Not adding `{}` to {:?} as it would require reformatting the file, please add it manually",
+            skipped,
+            path
+        );
+    }
+
+    log::debug!("Merged {:?} using {:?}", path, handler);
+    Some(outcome.content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_handler_for_path() {
+        assert_eq!(
+            MergeHandler::for_path(&PathBuf::from(".gitignore")),
+            Some(MergeHandler::IgnoreFile)
+        );
+        assert_eq!(
+            MergeHandler::for_path(&PathBuf::from("some/dir/.dockerignore")),
+            Some(MergeHandler::IgnoreFile)
+        );
+        assert_eq!(
+            MergeHandler::for_path(&PathBuf::from(".envrc")),
+            Some(MergeHandler::Envrc)
+        );
+        assert_eq!(
+            MergeHandler::for_path(&PathBuf::from(".gitlab-ci.yml")),
+            Some(MergeHandler::Yaml)
+        );
+        assert_eq!(
+            MergeHandler::for_path(&PathBuf::from(".github/dependabot.yaml")),
+            Some(MergeHandler::Yaml)
+        );
+        assert_eq!(
+            MergeHandler::for_path(&PathBuf::from("flake.nix")),
+            Some(MergeHandler::Nix)
+        );
+        assert_eq!(MergeHandler::for_path(&PathBuf::from("README.md")), None);
+        assert_eq!(MergeHandler::for_path(&PathBuf::from("ignore")), None);
+    }
+
+    #[test]
+    fn test_merge_contents_without_handler() {
+        assert_eq!(
+            merge_contents(&PathBuf::from("README.md"), "ours", "theirs"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_merge_contents_with_handler() {
+        assert_eq!(
+            merge_contents(&PathBuf::from(".gitignore"), "result\n", ".direnv\n"),
+            Some("result\n\n.direnv\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_merge_contents_invalid_yaml() {
+        assert_eq!(
+            merge_contents(&PathBuf::from(".gitlab-ci.yml"), "jobs: [", "jobs: {}"),
+            None
+        );
+    }
+}
@@ -0,0 +1,667 @@
+//! Syntax tree aware merging of `.nix` files. Attribute sets are merged by
+//! their attribute paths, while everything that's already present in the
+//! user's file (comments, formatting, ordering) is left as it is.
+use rnix::types::{
+    AttrSet, EntryHolder, KeyValue, Lambda, LetIn, Pattern, TokenWrapper, TypedNode, Wrapper,
+};
+use rnix::{NodeOrToken, SyntaxKind, SyntaxNode, TextRange};
+use std::collections::BTreeMap;
+
+use crate::merge::{MergeError, MergeOutcome};
+
+/// Normalized representation of a node used for comparisons, that is,
+/// all of the tokens without whitespace and comments.
+fn normalize(node: &SyntaxNode) -> String {
+    node.descendants_with_tokens()
+        .filter_map(|element| match element {
+            NodeOrToken::Token(token)
+                if !matches!(
+                    token.kind(),
+                    SyntaxKind::TOKEN_WHITESPACE | SyntaxKind::TOKEN_COMMENT
+                ) =>
+            {
+                Some(token.text().to_string())
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub(crate) fn key_segments(entry: &KeyValue) -> Vec<String> {
+    entry
+        .key()
+        .map(|key| {
+            key.path()
+                .map(|segment| {
+                    let text = segment.text().to_string();
+                    match text.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+                        Some(unquoted) if !unquoted.contains("${") => unquoted.to_string(),
+                        _ => text,
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Strips parentheses, `with` expressions and the root node, since they
+/// don't influence the structure we are merging.
+fn unwrap_node(mut node: SyntaxNode) -> Option<SyntaxNode> {
+    loop {
+        node = match node.kind() {
+            SyntaxKind::NODE_ROOT => rnix::types::Root::cast(node)?.inner()?,
+            SyntaxKind::NODE_PAREN => rnix::types::Paren::cast(node)?.inner()?,
+            SyntaxKind::NODE_WITH => rnix::types::With::cast(node)?.body()?,
+            _ => return Some(node),
+        }
+    }
+}
+
+/// Returns the indentation of a node placed on its own line.
+fn indentation_of(node: &SyntaxNode) -> Option<String> {
+    let whitespace = node.prev_sibling_or_token()?.into_token()?;
+    if whitespace.kind() != SyntaxKind::TOKEN_WHITESPACE || !whitespace.text().contains('\n') {
+        return None;
+    }
+    whitespace.text().rsplit('\n').next().map(str::to_string)
+}
+
+fn followed_by_newline(node: &SyntaxNode) -> bool {
+    node.next_sibling_or_token()
+        .and_then(|next| next.into_token())
+        .is_some_and(|whitespace| {
+            whitespace.kind() == SyntaxKind::TOKEN_WHITESPACE && whitespace.text().contains('\n')
+        })
+}
+
+pub(crate) fn as_attrset(node: &SyntaxNode) -> Option<AttrSet> {
+    unwrap_node(node.clone()).and_then(AttrSet::cast)
+}
+
+/// Entry of the user's file replaced by conflict markers, all of the
+/// conflicting values of the part are listed in its `theirs` section.
+struct ConflictBlock {
+    range: TextRange,
+    indent: String,
+    before: &'static str,
+    after: &'static str,
+    ours: String,
+    theirs: Vec<String>,
+}
+
+impl ConflictBlock {
+    fn render(&self) -> String {
+        let theirs = self
+            .theirs
+            .iter()
+            .map(|entry| format!("{}{}\n", self.indent, entry))
+            .collect::<String>();
+        format!(
+            "{}<<<<<<< ours\n{}{}\n=======\n{}>>>>>>> theirs{}",
+            self.before, self.indent, self.ours, theirs, self.after
+        )
+    }
+}
+
+struct NixMerger {
+    /// Pending insertions keyed by their offset in the user's file
+    insertions: BTreeMap<usize, Vec<String>>,
+    replacements: Vec<ConflictBlock>,
+    conflicts: Vec<String>,
+}
+
+impl NixMerger {
+    fn new() -> Self {
+        Self {
+            insertions: BTreeMap::new(),
+            replacements: Vec::new(),
+            conflicts: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, offset: usize, text: String) {
+        self.insertions.entry(offset).or_default().push(text);
+    }
+
+    /// Walks both expressions in lockstep through lambdas and let bindings
+    /// down to the attribute sets that get merged.
+    fn merge_expr(&mut self, ours: SyntaxNode, theirs: SyntaxNode) -> Result<(), MergeError> {
+        let ours = unwrap_node(ours).ok_or(MergeError::NixStructureError)?;
+        let theirs = unwrap_node(theirs).ok_or(MergeError::NixStructureError)?;
+
+        match (ours.kind(), theirs.kind()) {
+            (SyntaxKind::NODE_LAMBDA, SyntaxKind::NODE_LAMBDA) => {
+                let ours = Lambda::cast(ours).ok_or(MergeError::NixStructureError)?;
+                let theirs = Lambda::cast(theirs).ok_or(MergeError::NixStructureError)?;
+
+                if let (Some(our_pattern), Some(their_pattern)) = (
+                    ours.arg().and_then(Pattern::cast),
+                    theirs.arg().and_then(Pattern::cast),
+                ) {
+                    self.merge_patterns(&our_pattern, &their_pattern);
+                }
+
+                match (ours.body(), theirs.body()) {
+                    (Some(our_body), Some(their_body)) => self.merge_expr(our_body, their_body),
+                    _ => Err(MergeError::NixStructureError),
+                }
+            }
+            (SyntaxKind::NODE_LET_IN, SyntaxKind::NODE_LET_IN) => {
+                let ours = LetIn::cast(ours).ok_or(MergeError::NixStructureError)?;
+                let theirs = LetIn::cast(theirs).ok_or(MergeError::NixStructureError)?;
+
+                self.merge_entries(ours.node(), theirs.node(), &[]);
+
+                match (ours.body(), theirs.body()) {
+                    (Some(our_body), Some(their_body)) => self.merge_expr(our_body, their_body),
+                    _ => Err(MergeError::NixStructureError),
+                }
+            }
+            (_, SyntaxKind::NODE_LET_IN) => Err(MergeError::NixStructureError),
+            (SyntaxKind::NODE_LET_IN, _) => {
+                let ours = LetIn::cast(ours).ok_or(MergeError::NixStructureError)?;
+                let our_body = ours.body().ok_or(MergeError::NixStructureError)?;
+                self.merge_expr(our_body, theirs)
+            }
+            (SyntaxKind::NODE_ATTR_SET, SyntaxKind::NODE_ATTR_SET) => {
+                self.merge_entries(&ours, &theirs, &[]);
+                Ok(())
+            }
+            _ if normalize(&ours) == normalize(&theirs) => Ok(()),
+            _ => Err(MergeError::NixStructureError),
+        }
+    }
+
+    /// Adds missing formal arguments, eg. `{ lib, ... }` and
+    /// `{ config, ... }` result in `{ lib, config, ... }`.
+    fn merge_patterns(&mut self, ours: &Pattern, theirs: &Pattern) {
+        let our_names = ours
+            .entries()
+            .filter_map(|entry| entry.name())
+            .map(|name| name.as_str().to_string())
+            .collect::<Vec<_>>();
+
+        let missing = theirs
+            .entries()
+            .filter(|entry| {
+                entry
+                    .name()
+                    .is_some_and(|name| !our_names.iter().any(|n| n == name.as_str()))
+            })
+            .map(|entry| entry.node().text().to_string())
+            .collect::<Vec<_>>();
+
+        if missing.is_empty() {
+            return;
+        }
+
+        match ours.entries().last() {
+            Some(last) => {
+                let offset = usize::from(last.node().text_range().end());
+                self.insert(offset, format!(", {}", missing.join(", ")));
+            }
+            None => {
+                // NOTE pattern without any entries, eg. `{ ... }` or `{ }`
+                let Some(open_brace) = ours
+                    .node()
+                    .children_with_tokens()
+                    .find(|element| element.kind() == SyntaxKind::TOKEN_CURLY_B_OPEN)
+                else {
+                    return;
+                };
+                let offset = usize::from(open_brace.text_range().end());
+                let separator = if ours.ellipsis() { "," } else { "" };
+                self.insert(offset, format!(" {}{}", missing.join(", "), separator));
+            }
+        }
+    }
+
+    /// Merges all entries (`key = value;` and `inherit`s) of `theirs` into
+    /// `ours`, which is either an attribute set or a let binding.
+    fn merge_entries(&mut self, ours: &SyntaxNode, theirs: &SyntaxNode, path: &[String]) {
+        let their_entries = theirs.children().filter_map(KeyValue::cast);
+        for their_entry in their_entries {
+            let (Some(their_value), segments) = (their_entry.value(), key_segments(&their_entry))
+            else {
+                continue;
+            };
+            self.merge_entry(ours, &segments, &their_value, path);
+        }
+
+        let our_inherits = ours
+            .children()
+            .filter(|node| node.kind() == SyntaxKind::NODE_INHERIT)
+            .map(|node| normalize(&node))
+            .collect::<Vec<_>>();
+
+        let missing_inherits = theirs
+            .children()
+            .filter(|node| node.kind() == SyntaxKind::NODE_INHERIT)
+            .filter(|node| !our_inherits.contains(&normalize(node)))
+            .map(|node| node.text().to_string())
+            .collect::<Vec<_>>();
+
+        for inherit in missing_inherits {
+            self.insert_entry(ours, inherit);
+        }
+    }
+
+    fn merge_entry(
+        &mut self,
+        ours: &SyntaxNode,
+        segments: &[String],
+        their_value: &SyntaxNode,
+        path: &[String],
+    ) {
+        let full_path = [path, segments].concat();
+
+        for our_entry in ours.children().filter_map(KeyValue::cast) {
+            let our_segments = key_segments(&our_entry);
+            let Some(our_value) = our_entry.value() else {
+                continue;
+            };
+
+            if our_segments == segments {
+                return self.merge_values(&our_entry, &our_value, their_value, &full_path);
+            }
+
+            // eg. `a = { b = 1; };` in ours and `a.c = 2;` in theirs
+            if segments.starts_with(&our_segments) {
+                return match as_attrset(&our_value) {
+                    Some(our_set) => self.merge_entry(
+                        our_set.node(),
+                        &segments[our_segments.len()..],
+                        their_value,
+                        &[path, &our_segments].concat(),
+                    ),
+                    None => self.conflict(&our_entry, segments, their_value, &full_path),
+                };
+            }
+        }
+
+        // eg. `a.b = 1;` in ours and `a = { c = 2; };` in theirs
+        let has_nested_entries = ours.children().filter_map(KeyValue::cast).any(|entry| {
+            let our_segments = key_segments(&entry);
+            our_segments.len() > segments.len() && our_segments.starts_with(segments)
+        });
+
+        match as_attrset(their_value) {
+            Some(their_set) if has_nested_entries && !their_set.recursive() => {
+                for their_entry in their_set.entries() {
+                    if let Some(value) = their_entry.value() {
+                        let nested = [segments, &key_segments(&their_entry)].concat();
+                        self.merge_entry(ours, &nested, &value, path);
+                    }
+                }
+            }
+            _ => {
+                let entry = format!("{} = {};", segments.join("."), their_value.text());
+                self.insert_entry(ours, entry);
+            }
+        }
+    }
+
+    fn merge_values(
+        &mut self,
+        our_entry: &KeyValue,
+        our_value: &SyntaxNode,
+        their_value: &SyntaxNode,
+        path: &[String],
+    ) {
+        if normalize(our_value) == normalize(their_value) {
+            return;
+        }
+
+        match (as_attrset(our_value), as_attrset(their_value)) {
+            (Some(our_set), Some(their_set)) if our_set.recursive() == their_set.recursive() => {
+                self.merge_entries(our_set.node(), their_set.node(), path);
+            }
+            _ => self.conflict(our_entry, &key_segments(our_entry), their_value, path),
+        }
+    }
+
+    fn conflict(
+        &mut self,
+        our_entry: &KeyValue,
+        segments: &[String],
+        their_value: &SyntaxNode,
+        path: &[String],
+    ) {
+        let node = our_entry.node();
+        // NOTE conflict markers need to be on their own lines, entries that
+        // don't start or end a line (eg. `{ a = 1; }`) are moved to new ones
+        let (indent, before) = match indentation_of(node) {
+            Some(indent) => (indent, ""),
+            None => (String::new(), "\n"),
+        };
+        let after = if followed_by_newline(node) { "" } else { "\n" };
+        let start = usize::from(node.text_range().start()) - indent.len();
+        let end = usize::from(node.text_range().end());
+
+        let range = TextRange::new((start as u32).into(), (end as u32).into());
+        let entry = format!("{} = {};", segments.join("."), their_value.text());
+
+        self.conflicts.push(path.join("."));
+        // NOTE the same entry can conflict multiple times, eg. with both
+        // `a.b` and `a.c` of the part, these share a single block
+        match self
+            .replacements
+            .iter_mut()
+            .find(|block| block.range == range)
+        {
+            Some(block) if block.theirs.contains(&entry) => {}
+            Some(block) => block.theirs.push(entry),
+            None => self.replacements.push(ConflictBlock {
+                range,
+                indent,
+                before,
+                after,
+                ours: node.text().to_string(),
+                theirs: vec![entry],
+            }),
+        }
+    }
+
+    /// Inserts a new entry after the last entry of `holder`, reusing its
+    /// indentation, or right after the opening brace of an empty set.
+    fn insert_entry(&mut self, holder: &SyntaxNode, entry: String) {
+        let last_entry = holder
+            .children()
+            .filter(|node| {
+                matches!(
+                    node.kind(),
+                    SyntaxKind::NODE_KEY_VALUE | SyntaxKind::NODE_INHERIT
+                )
+            })
+            .last();
+
+        match last_entry {
+            Some(last) => {
+                let separator = match indentation_of(&last) {
+                    Some(indent) => format!("\n{}", indent),
+                    None => " ".to_string(),
+                };
+                let offset = usize::from(last.text_range().end());
+                self.insert(offset, format!("{}{}", separator, entry));
+            }
+            None => {
+                let Some(open_brace) = holder
+                    .children_with_tokens()
+                    .find(|element| element.kind() == SyntaxKind::TOKEN_CURLY_B_OPEN)
+                else {
+                    return;
+                };
+                let offset = usize::from(open_brace.text_range().end());
+                self.insert(offset, format!(" {}", entry));
+            }
+        }
+    }
+
+    /// Applies the pending edits to `source`, edits overlapping each other
+    /// (eg. conflicts of both an attribute and the set containing it) can't
+    /// be applied without corrupting the file.
+    fn apply(self, source: &str) -> Result<MergeOutcome, MergeError> {
+        let mut ranges = self
+            .replacements
+            .iter()
+            .map(|block| {
+                (
+                    usize::from(block.range.start()),
+                    usize::from(block.range.end()),
+                )
+            })
+            .collect::<Vec<_>>();
+        ranges.sort();
+        let overlapping = ranges.windows(2).any(|pair| pair[1].0 < pair[0].1)
+            || self.insertions.keys().any(|offset| {
+                ranges
+                    .iter()
+                    .any(|(start, end)| start < offset && offset < end)
+            });
+        if overlapping {
+            return Err(MergeError::NixOverlapError);
+        }
+
+        let mut edits = self
+            .insertions
+            .into_iter()
+            .map(|(offset, texts)| (offset, offset, texts.concat()))
+            .chain(self.replacements.iter().map(|block| {
+                (
+                    usize::from(block.range.start()),
+                    usize::from(block.range.end()),
+                    block.render(),
+                )
+            }))
+            .collect::<Vec<_>>();
+
+        edits.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(&a.1)));
+
+        let mut content = source.to_string();
+        for (start, end, text) in edits {
+            content.replace_range(start..end, &text);
+        }
+
+        Ok(MergeOutcome {
+            content,
+            conflicts: self.conflicts,
+            skipped: Vec::new(),
+        })
+    }
+}
+
+/// Merges `theirs` into `ours` by attribute paths. Missing attributes (and
+/// formal arguments of modules) are added, attributes defined on both sides
+/// with different values are marked with git-like conflict markers.
+pub fn merge_nix(ours: &str, theirs: &str) -> Result<MergeOutcome, MergeError> {
+    let our_ast = rnix::parse(ours).as_result()?;
+    let their_ast = rnix::parse(theirs).as_result()?;
+
+    let mut merger = NixMerger::new();
+    merger.merge_expr(our_ast.node(), their_ast.node())?;
+
+    merger.apply(ours)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_nix_adds_missing_attributes() -> Result<(), MergeError> {
+        let ours = r#"{
+  # my comment
+  inputs = {
+    nixpkgs.url = "github:nixos/nixpkgs/nixos-unstable";
+    my-input.url = "github:me/my-input";
+  };
+}"#;
+        let theirs = r#"{
+  inputs = {
+    nixpkgs.url = "github:nixos/nixpkgs/nixos-unstable";
+    treefmt-nix.url = "github:numtide/treefmt-nix";
+  };
+}"#;
+
+        let outcome = merge_nix(ours, theirs)?;
+        assert!(outcome.conflicts.is_empty());
+        assert_eq!(
+            outcome.content,
+            r#"{
+  # my comment
+  inputs = {
+    nixpkgs.url = "github:nixos/nixpkgs/nixos-unstable";
+    my-input.url = "github:me/my-input";
+    treefmt-nix.url = "github:numtide/treefmt-nix";
+  };
+}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_nix_ignores_formatting() -> Result<(), MergeError> {
+        let ours = "{ a = {\n    b = 1;\n  }; }";
+        let theirs = "{ a = { b = 1; }; }";
+
+        let outcome = merge_nix(ours, theirs)?;
+        assert!(outcome.conflicts.is_empty());
+        assert_eq!(outcome.content, ours);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_nix_dotted_paths() -> Result<(), MergeError> {
+        let ours = "{\n  a.b = 1;\n}";
+        let theirs = "{\n  a = {\n    b = 1;\n    c = 2;\n  };\n}";
+
+        let outcome = merge_nix(ours, theirs)?;
+        assert!(outcome.conflicts.is_empty());
+        assert_eq!(outcome.content, "{\n  a.b = 1;\n  a.c = 2;\n}");
+
+        let ours = "{\n  a = {\n    b = 1;\n  };\n}";
+        let theirs = "{\n  a.c = 2;\n}";
+
+        let outcome = merge_nix(ours, theirs)?;
+        assert_eq!(
+            outcome.content,
+            "{\n  a = {\n    b = 1;\n    c = 2;\n  };\n}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_nix_conflict_markers() -> Result<(), MergeError> {
+        let ours = "{\n  a = 1;\n  b = 2;\n}";
+        let theirs = "{\n  a = 3;\n  b = 2;\n}";
+
+        let outcome = merge_nix(ours, theirs)?;
+        assert_eq!(outcome.conflicts, vec!["a"]);
+        assert_eq!(
+            outcome.content,
+            "{\n<<<<<<< ours\n  a = 1;\n=======\n  a = 3;\n>>>>>>> theirs\n  b = 2;\n}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_nix_conflict_markers_inline() -> Result<(), MergeError> {
+        let ours = "{ a = 1; b = 2; }";
+        let theirs = "{ a = 3; }";
+
+        let outcome = merge_nix(ours, theirs)?;
+        assert_eq!(outcome.conflicts, vec!["a"]);
+        assert_eq!(
+            outcome.content,
+            "{ \n<<<<<<< ours\na = 1;\n=======\na = 3;\n>>>>>>> theirs\n b = 2; }"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_nix_repeated_conflict() -> Result<(), MergeError> {
+        let ours = "{\n  a = 1;\n}";
+        let theirs = "{\n  a.b = 2;\n  a.c = 3;\n}";
+
+        let outcome = merge_nix(ours, theirs)?;
+        assert_eq!(outcome.conflicts, vec!["a.b", "a.c"]);
+        assert_eq!(
+            outcome.content,
+            "{\n<<<<<<< ours\n  a = 1;\n=======\n  a.b = 2;\n  a.c = 3;\n>>>>>>> theirs\n}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_nix_overlapping_conflicts() {
+        let ours = "{\n  a = {\n    b = 1;\n  };\n}";
+        let theirs = "{\n  a.b = 2;\n  a = 3;\n}";
+
+        assert!(matches!(
+            merge_nix(ours, theirs),
+            Err(MergeError::NixOverlapError)
+        ));
+    }
+
+    #[test]
+    fn test_merge_nix_modules() -> Result<(), MergeError> {
+        let ours = r#"{ lib, ... }:
+let
+  foo = 1;
+in
+{
+  perSystem = { pkgs, ... }: {
+    packages.default = pkgs.hello;
+  };
+}"#;
+        let theirs = r#"{ lib, inputs, ... }:
+let
+  bar = 2;
+in
+{
+  imports = [ inputs.treefmt-nix.flakeModule ];
+}"#;
+
+        let outcome = merge_nix(ours, theirs)?;
+        assert!(outcome.conflicts.is_empty());
+        assert_eq!(
+            outcome.content,
+            r#"{ lib, inputs, ... }:
+let
+  foo = 1;
+  bar = 2;
+in
+{
+  perSystem = { pkgs, ... }: {
+    packages.default = pkgs.hello;
+  };
+  imports = [ inputs.treefmt-nix.flakeModule ];
+}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_nix_inherits() -> Result<(), MergeError> {
+        let ours = "{\n  inherit (lib) mkIf;\n}";
+        let theirs = "{\n  inherit (lib) mkIf;\n  inherit pkgs;\n}";
+
+        let outcome = merge_nix(ours, theirs)?;
+        assert_eq!(
+            outcome.content,
+            "{\n  inherit (lib) mkIf;\n  inherit pkgs;\n}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_nix_empty_set() -> Result<(), MergeError> {
+        let outcome = merge_nix("{ }", "{ a = 1; }")?;
+        assert_eq!(outcome.content, "{ a = 1; }");
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_nix_idempotent() -> Result<(), MergeError> {
+        let ours = "{ config, ... }:\n{\n  a = 1;\n}";
+        let theirs = "{ lib, ... }:\n{\n  b.c = 2;\n  inherit (lib) mkIf;\n}";
+
+        let merged = merge_nix(ours, theirs)?.content;
+        let merged_again = merge_nix(&merged, theirs)?.content;
+        assert_eq!(merged, merged_again);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_nix_invalid() {
+        assert!(matches!(
+            merge_nix("{ a = ; }", "{ }"),
+            Err(MergeError::NixParseError(_))
+        ));
+        assert!(matches!(
+            merge_nix("{ a = 1; }", "let b = 1; in { }"),
+            Err(MergeError::NixStructureError)
+        ));
+    }
+}
@@ -0,0 +1,172 @@
+//! Merge handlers for plain text files that are merged line by line or
+//! paragraph by paragraph.
+use std::collections::HashSet;
+
+/// Appends a block of new lines to `ours`, separated by a single empty line.
+fn append_block(ours: &str, block: &[&str]) -> String {
+    if block.is_empty() {
+        return ours.to_string();
+    }
+
+    let mut merged = ours.trim_end().to_string();
+    if !merged.is_empty() {
+        merged.push_str("\n\n");
+    }
+    merged.push_str(&block.join("\n"));
+    merged.push('\n');
+    merged
+}
+
+pub fn merge_ignore_file(ours: &str, theirs: &str) -> String {
+    let mut known = ours.lines().map(str::trim).collect::<HashSet<_>>();
+    let mut block = Vec::new();
+    // Comments are carried over only together with a new pattern that
+    // follows them, so that already present sections aren't duplicated
+    let mut pending_comments = Vec::new();
+
+    for line in theirs.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            pending_comments.clear();
+        } else if trimmed.starts_with('#') {
+            pending_comments.push(line);
+        } else if known.insert(trimmed) {
+            block.extend(
+                pending_comments
+                    .drain(..)
+                    .filter(|comment| !ours.lines().any(|l| l.trim() == comment.trim())),
+            );
+            block.push(line);
+        }
+    }
+
+    append_block(ours, &block)
+}
+
+pub fn merge_envrc(ours: &str, theirs: &str) -> String {
+    let normalize = |s: &str| s.split_whitespace().collect::<Vec<_>>().join(" ");
+    let normalized_ours = ours
+        .lines()
+        .map(normalize)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+    // A paragraph is present only if all of its lines occur in `ours` as
+    // complete consecutive lines
+    let is_present = |paragraph: &[&str]| {
+        let normalized = paragraph
+            .iter()
+            .map(|line| normalize(line))
+            .collect::<Vec<_>>();
+        normalized_ours
+            .windows(normalized.len())
+            .any(|window| window == normalized.as_slice())
+    };
+
+    // NOTE shell snippets can't be deduplicated line by line without
+    // breaking control flow (eg. `fi`), so we work with whole paragraphs
+    let mut block = Vec::new();
+    let mut paragraph = Vec::new();
+    let mut lines = theirs.lines().peekable();
+
+    if !ours.trim().is_empty() {
+        lines.next_if(|line| line.starts_with("#!"));
+    }
+
+    for line in lines.chain(std::iter::once("")) {
+        if !line.trim().is_empty() {
+            paragraph.push(line);
+            continue;
+        }
+        if paragraph.is_empty() {
+            continue;
+        }
+        if !is_present(&paragraph) {
+            if !block.is_empty() {
+                block.push("");
+            }
+            block.append(&mut paragraph);
+        }
+        paragraph.clear();
+    }
+
+    append_block(ours, &block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_ignore_file_union() {
+        let ours = "result\n.direnv\n";
+        let theirs = "## devenv\n.devenv\n\n.direnv\nresult-*\n";
+
+        let merged = merge_ignore_file(ours, theirs);
+        assert_eq!(merged, "result\n.direnv\n\n## devenv\n.devenv\nresult-*\n");
+    }
+
+    #[test]
+    fn test_merge_ignore_file_skips_known_sections() {
+        let ours = "# Nix\nresult\n";
+        let theirs = "# Nix\nresult\n";
+
+        let merged = merge_ignore_file(ours, theirs);
+        assert_eq!(merged, ours);
+    }
+
+    #[test]
+    fn test_merge_ignore_file_idempotent() {
+        let ours = "result\n";
+        let theirs = "# Editors\n*.swp\n.idea/\n";
+
+        let merged = merge_ignore_file(ours, theirs);
+        let merged_again = merge_ignore_file(&merged, theirs);
+        assert_eq!(merged, merged_again);
+    }
+
+    #[test]
+    fn test_merge_ignore_file_into_empty() {
+        let merged = merge_ignore_file("", "result\n");
+        assert_eq!(merged, "result\n");
+    }
+
+    #[test]
+    fn test_merge_envrc_appends_new_paragraphs() {
+        let ours = "#!/usr/bin/env bash\n\nwatch_file flake.nix\n\nuse flake\n";
+        let theirs = "#!/usr/bin/env bash\n\nwatch_file flake.nix\n\nif true; then\n  dotenv\nfi\n";
+
+        let merged = merge_envrc(ours, theirs);
+        assert_eq!(
+            merged,
+            "#!/usr/bin/env bash\n\nwatch_file flake.nix\n\nuse flake\n\nif true; then\n  dotenv\nfi\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_envrc_ignores_whitespace_changes() {
+        let ours = "if true; then\n    dotenv\nfi\n";
+        let theirs = "if true; then\n  dotenv\nfi\n";
+
+        let merged = merge_envrc(ours, theirs);
+        assert_eq!(merged, ours);
+    }
+
+    #[test]
+    fn test_merge_envrc_prefix_isnt_present() {
+        let ours = "use flake .#dev\n";
+        let theirs = "use flake\n";
+
+        let merged = merge_envrc(ours, theirs);
+        assert_eq!(merged, "use flake .#dev\n\nuse flake\n");
+    }
+
+    #[test]
+    fn test_merge_envrc_idempotent() {
+        let ours = "use flake\n";
+        let theirs = "#!/usr/bin/env bash\nwatch_file flake.lock\n\ndotenv_if_exists\n";
+
+        let merged = merge_envrc(ours, theirs);
+        let merged_again = merge_envrc(&merged, theirs);
+        assert_eq!(merged, merged_again);
+    }
+}
@@ -0,0 +1,618 @@
+//! Structural merging of YAML files, mostly used for CI workflows that are
+//! contributed by multiple parts and further customised by the user.
+use serde_yaml::{Mapping, Value};
+
+use crate::merge::{MergeError, MergeOutcome};
+
+/// Keys used to identify items in sequences of mappings, for example
+/// GitHub Actions `steps`, ordered by priority.
+static IDENTITY_KEYS: [&str; 4] = ["id", "name", "uses", "run"];
+
+fn key_to_string(key: &Value) -> String {
+    match key {
+        Value::String(s) => s.clone(),
+        other => serde_yaml::to_string(other)
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// Returns the first identity key present in both items together with
+/// whether their values of that key are equal.
+fn shared_identity(ours: &Value, theirs: &Value) -> Option<(&'static str, bool)> {
+    let (ours, theirs) = (ours.as_mapping()?, theirs.as_mapping()?);
+    IDENTITY_KEYS.iter().find_map(|&key| {
+        let (our_value, their_value) = (ours.get(key)?, theirs.get(key)?);
+        Some((key, our_value == their_value))
+    })
+}
+
+fn item_path(path: &str, item: &Value, index: usize) -> String {
+    let identity = item.as_mapping().and_then(|mapping| {
+        IDENTITY_KEYS
+            .iter()
+            .find_map(|&key| mapping.get(key).map(|value| (key, value)))
+    });
+
+    match identity {
+        Some((key, value)) => format!("{}[{}={}]", path, key, key_to_string(value)),
+        None => format!("{}[{}]", path, index),
+    }
+}
+
+/// Lines `start..end` of a block style node of the user's file, `indent`
+/// is the column of its keys or of its `-` item markers.
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    start: usize,
+    end: usize,
+    indent: usize,
+}
+
+/// The user's file together with the text to insert into it, values are
+/// only ever inserted so that the formatting and comments are kept.
+struct Document<'a> {
+    lines: Vec<&'a str>,
+    insertions: Vec<(usize, String)>,
+}
+
+fn is_content(line: &str) -> bool {
+    let trimmed = line.trim();
+    !trimmed.is_empty() && !trimmed.starts_with('#') && trimmed != "---" && trimmed != "..."
+}
+
+/// Column of the first character of `line` that isn't indentation or, in
+/// case of `skip_dashes`, a sequence item marker.
+fn content_column(line: &str, skip_dashes: bool) -> usize {
+    let bytes = line.as_bytes();
+    let mut column = 0;
+    loop {
+        while bytes.get(column) == Some(&b' ') {
+            column += 1;
+        }
+        if skip_dashes
+            && bytes.get(column) == Some(&b'-')
+            && matches!(bytes.get(column + 1), Some(b' ') | None)
+        {
+            column += 1;
+            continue;
+        }
+        return column;
+    }
+}
+
+/// Splits `key: value` at the colon separating the key, returns the parsed
+/// key and the rest of the line.
+fn split_key(text: &str) -> Option<(Value, &str)> {
+    let bytes = text.as_bytes();
+    let mut index = match bytes.first()? {
+        quote @ (b'"' | b'\'') => index_of(bytes, 1, *quote)? + 1,
+        _ => 0,
+    };
+    loop {
+        index = index_of(bytes, index, b':')?;
+        if matches!(bytes.get(index + 1), Some(b' ') | None) {
+            let key = serde_yaml::from_str(&text[..index]).ok()?;
+            return Some((key, &text[index + 1..]));
+        }
+        index += 1;
+    }
+}
+
+fn index_of(bytes: &[u8], from: usize, byte: u8) -> Option<usize> {
+    bytes
+        .get(from..)?
+        .iter()
+        .position(|&b| b == byte)
+        .map(|index| from + index)
+}
+
+/// Prefixes the non empty lines of `yaml` with `indent` spaces.
+fn indented(yaml: &str, indent: usize) -> String {
+    yaml.lines()
+        .map(|line| match line {
+            "" => "\n".to_string(),
+            _ => format!("{}{}\n", " ".repeat(indent), line),
+        })
+        .collect()
+}
+
+impl<'a> Document<'a> {
+    fn new(contents: &'a str) -> Self {
+        Self {
+            lines: contents.split_inclusive('\n').collect(),
+            insertions: Vec::new(),
+        }
+    }
+
+    fn root(&self) -> Option<Block> {
+        let first = self.lines.iter().find(|line| is_content(line))?;
+        Some(Block {
+            start: 0,
+            end: self.lines.len(),
+            indent: content_column(first, false),
+        })
+    }
+
+    /// Line of the `key` entry of the mapping `block`.
+    fn entry(&self, block: Block, key: &Value) -> Option<usize> {
+        (block.start..block.end).find(|&index| {
+            let line = self.lines[index].trim_end();
+            let column = content_column(line, index == block.start);
+            is_content(line)
+                && column == block.indent
+                && split_key(&line[column..]).is_some_and(|(line_key, _)| &line_key == key)
+        })
+    }
+
+    /// Block of the value of the entry at line `index` of `parent`, `None`
+    /// if the value is written on the same line (eg. in the flow style).
+    fn value_block(&self, parent: Block, index: usize) -> Option<Block> {
+        let line = self.lines[index].trim_end();
+        let column = content_column(line, index == parent.start);
+        let (_, rest) = split_key(&line[column..])?;
+        let rest = rest.trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return None;
+        }
+
+        let end = (index + 1..parent.end)
+            .find(|&index| {
+                let line = self.lines[index];
+                let indent = content_column(line, false);
+                is_content(line)
+                    && (indent < column || (indent == column && !line[indent..].starts_with('-')))
+            })
+            .unwrap_or(parent.end);
+        let first = (index + 1..end).find(|&index| is_content(self.lines[index]))?;
+
+        Some(Block {
+            start: index + 1,
+            end,
+            indent: content_column(self.lines[first], false),
+        })
+    }
+
+    /// Blocks of the items of the sequence `block`, `None` if there isn't
+    /// exactly `len` of them.
+    fn items(&self, block: Block, len: usize) -> Option<Vec<Block>> {
+        let starts = (block.start..block.end)
+            .filter(|&index| {
+                let line = self.lines[index];
+                is_content(line)
+                    && content_column(line, false) == block.indent
+                    && line[block.indent..].starts_with('-')
+            })
+            .collect::<Vec<_>>();
+        if starts.len() != len {
+            return None;
+        }
+
+        starts
+            .iter()
+            .enumerate()
+            .map(|(item, &start)| {
+                let end = starts.get(item + 1).copied().unwrap_or(block.end);
+                let first = (start..end).find(|&index| {
+                    let line = self.lines[index];
+                    is_content(line) && content_column(line, index == start) < line.trim_end().len()
+                })?;
+                Some(Block {
+                    start,
+                    end,
+                    indent: content_column(self.lines[first], first == start),
+                })
+            })
+            .collect()
+    }
+
+    /// Inserts `yaml` at the end of `block`, indented to its level.
+    fn append(&mut self, block: Block, yaml: &str) {
+        let last = (block.start..block.end)
+            .rev()
+            .find(|&index| is_content(self.lines[index]))
+            .unwrap_or(block.start);
+        self.insertions
+            .push((last + 1, indented(yaml, block.indent)));
+    }
+
+    fn render(&self) -> String {
+        let mut rendered = String::new();
+        for index in 0..=self.lines.len() {
+            for (_, text) in self.insertions.iter().filter(|(at, _)| *at == index) {
+                if !rendered.is_empty() && !rendered.ends_with('\n') {
+                    rendered.push('\n');
+                }
+                rendered.push_str(text);
+            }
+            if let Some(line) = self.lines.get(index) {
+                rendered.push_str(line);
+            }
+        }
+        rendered
+    }
+}
+
+/// Walks `theirs` and inserts the values missing in `ours` into `doc`, the
+/// values that differ are reported as conflicts. Values can be inserted
+/// only into block style nodes, the ones missing in nodes written in the
+/// flow style are reported as skipped.
+fn merge_values(
+    doc: &mut Document,
+    ours: &Value,
+    theirs: &Value,
+    block: Option<Block>,
+    path: &str,
+    outcome: &mut MergeOutcome,
+) -> Result<(), MergeError> {
+    match (ours, theirs) {
+        (Value::Mapping(our_mapping), Value::Mapping(their_mapping)) => {
+            for (key, their_value) in their_mapping {
+                let key_path = match path {
+                    "" => key_to_string(key),
+                    _ => format!("{}.{}", path, key_to_string(key)),
+                };
+                match (our_mapping.get(key), block) {
+                    (Some(our_value), _) => {
+                        let value_block = block.and_then(|block| {
+                            doc.entry(block, key)
+                                .and_then(|index| doc.value_block(block, index))
+                        });
+                        merge_values(doc, our_value, their_value, value_block, &key_path, outcome)?
+                    }
+                    (None, Some(block)) => {
+                        let mut added = Mapping::new();
+                        added.insert(key.clone(), their_value.clone());
+                        doc.append(block, &serde_yaml::to_string(&added)?);
+                    }
+                    (None, None) => outcome.skipped.push(key_path),
+                }
+            }
+        }
+        (Value::Sequence(our_items), Value::Sequence(their_items)) => {
+            let item_blocks = block.and_then(|block| doc.items(block, our_items.len()));
+            for (index, their_item) in their_items.iter().enumerate() {
+                if our_items.contains(their_item) {
+                    continue;
+                }
+
+                // NOTE items sharing an identity are merged, all of the
+                // others (including plain values) are appended
+                let same = our_items.iter().position(|our_item| {
+                    shared_identity(our_item, their_item).is_some_and(|(_, equal)| equal)
+                });
+                let item_path = item_path(path, their_item, index);
+                match (same, block) {
+                    (Some(same), _) => {
+                        let item_block = item_blocks.as_ref().map(|blocks| blocks[same]);
+                        merge_values(
+                            doc,
+                            &our_items[same],
+                            their_item,
+                            item_block,
+                            &item_path,
+                            outcome,
+                        )?
+                    }
+                    (None, Some(block)) => {
+                        let added = Value::Sequence(vec![their_item.clone()]);
+                        doc.append(block, &serde_yaml::to_string(&added)?);
+                    }
+                    (None, None) => outcome.skipped.push(item_path),
+                }
+            }
+        }
+        (Value::Null, Value::Null) => {}
+        (Value::Null, _) => outcome.skipped.push(path.to_string()),
+        (ours, theirs) => {
+            if ours != theirs {
+                outcome.conflicts.push(path.to_string());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Merges `theirs` into `ours` by keys. Values missing in the user's file
+/// (eg. new CI jobs or steps) are inserted into it, so that its formatting
+/// and comments are kept. Sequences of mappings are matched by an identity
+/// key both items share (`id`, `name`, ...), other sequence items by their
+/// value. Differing scalar values are reported as conflicts and the user's
+/// version is kept.
+pub fn merge_yaml(ours: &str, theirs: &str) -> Result<MergeOutcome, MergeError> {
+    let our_value: Value = serde_yaml::from_str(ours)?;
+    let their_value: Value = serde_yaml::from_str(theirs)?;
+
+    let mut outcome = MergeOutcome {
+        content: ours.to_string(),
+        conflicts: Vec::new(),
+        skipped: Vec::new(),
+    };
+
+    if our_value.is_null() {
+        outcome.content = theirs.to_string();
+        return Ok(outcome);
+    }
+
+    let mut doc = Document::new(ours);
+    let root = doc.root();
+    merge_values(&mut doc, &our_value, &their_value, root, "", &mut outcome)?;
+    outcome.content = doc.render();
+
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_yaml_keeps_user_jobs() -> Result<(), MergeError> {
+        let ours = r#"
+# Customised by the user
+my-job:
+  script:
+    - make   # keep me
+flake-check:
+  script:
+    - nix flake check
+"#;
+        let theirs = r#"
+flake-check:
+  script:
+    - nix flake check
+deploy:
+  script:
+    - nix run .#deploy
+"#;
+
+        let outcome = merge_yaml(ours, theirs)?;
+        let merged: Value = serde_yaml::from_str(&outcome.content)?;
+
+        assert!(outcome.conflicts.is_empty());
+        assert!(outcome.skipped.is_empty());
+        // NOTE the user's text is kept as it is and new jobs are appended
+        assert!(outcome.content.starts_with(ours));
+        assert_eq!(
+            merged
+                .as_mapping()
+                .unwrap()
+                .keys()
+                .map(key_to_string)
+                .collect::<Vec<_>>(),
+            vec!["my-job", "flake-check", "deploy"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_yaml_nested_additions() -> Result<(), MergeError> {
+        let ours = r#"
+jobs:
+  check:
+    steps:
+      - name: checkout
+        uses: actions/checkout@v4
+      # my own step
+      - name: my step
+        run: echo hi
+"#;
+        let theirs = r#"
+jobs:
+  check:
+    steps:
+      - name: checkout
+        uses: actions/checkout@v4
+        with:
+          fetch-depth: 0
+      - name: flake check
+        run: nix flake check
+"#;
+
+        let outcome = merge_yaml(ours, theirs)?;
+
+        assert!(outcome.conflicts.is_empty());
+        assert!(outcome.skipped.is_empty());
+        assert_eq!(
+            outcome.content,
+            r#"
+jobs:
+  check:
+    steps:
+      - name: checkout
+        uses: actions/checkout@v4
+        with:
+          fetch-depth: 0
+      # my own step
+      - name: my step
+        run: echo hi
+      - name: flake check
+        run: nix flake check
+"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_yaml_two_workflows() -> Result<(), MergeError> {
+        let first = r#"name: CI
+on:
+  push:
+jobs:
+  check:
+    runs-on: ubuntu-latest
+    steps:
+    - uses: actions/checkout@v4
+    - name: Check
+      run: nix flake check
+  fmt:
+    runs-on: ubuntu-latest
+    steps:
+    - uses: actions/checkout@v4
+"#;
+        let second = r#"name: CI
+on:
+  push:
+jobs:
+  check:
+    runs-on: ubuntu-latest
+    steps:
+    - uses: actions/checkout@v4
+    - name: Build
+      run: |
+        nix build
+        nix build .#docs
+  deploy:
+    runs-on: ubuntu-latest
+    steps:
+    - run: nix run .#deploy
+"#;
+
+        let outcome = merge_yaml(first, second)?;
+
+        assert!(outcome.conflicts.is_empty());
+        assert!(outcome.skipped.is_empty());
+        assert_eq!(
+            outcome.content,
+            r#"name: CI
+on:
+  push:
+jobs:
+  check:
+    runs-on: ubuntu-latest
+    steps:
+    - uses: actions/checkout@v4
+    - name: Check
+      run: nix flake check
+    - name: Build
+      run: |
+        nix build
+        nix build .#docs
+  fmt:
+    runs-on: ubuntu-latest
+    steps:
+    - uses: actions/checkout@v4
+  deploy:
+    runs-on: ubuntu-latest
+    steps:
+    - run: nix run .#deploy
+"#
+        );
+
+        let merged: Value = serde_yaml::from_str(&outcome.content)?;
+        let jobs = &merged["jobs"];
+        assert_eq!(jobs["check"]["steps"].as_sequence().unwrap().len(), 3);
+        assert_eq!(jobs["deploy"]["steps"][0]["run"], "nix run .#deploy");
+        assert_eq!(
+            merge_yaml(&outcome.content, second)?.content,
+            outcome.content
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_yaml_flow_style_skipped() -> Result<(), MergeError> {
+        let ours = "check: {script: [make]}\nbranches: [main]\n";
+        let theirs = "check:\n  script:\n    - make\n  stage: test\nbranches:\n  - release\n";
+
+        let outcome = merge_yaml(ours, theirs)?;
+
+        assert!(outcome.conflicts.is_empty());
+        assert_eq!(outcome.skipped, vec!["check.stage", "branches[0]"]);
+        assert_eq!(outcome.content, ours);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_yaml_shared_identity() -> Result<(), MergeError> {
+        let ours = r#"
+steps:
+  - id: build
+    name: Build
+    run: make
+"#;
+        let theirs = r#"
+steps:
+  - name: Build
+    run: nix build
+"#;
+
+        let outcome = merge_yaml(ours, theirs)?;
+
+        assert_eq!(outcome.conflicts, vec!["steps[name=Build].run"]);
+        assert!(outcome.skipped.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_yaml_reports_conflicts() -> Result<(), MergeError> {
+        let ours = r#"
+jobs:
+  check:
+    runs-on: self-hosted
+    steps:
+      - name: checkout
+        uses: actions/checkout@v3
+"#;
+        let theirs = r#"
+jobs:
+  check:
+    runs-on: ubuntu-latest
+    steps:
+      - name: checkout
+        uses: actions/checkout@v4
+"#;
+
+        let outcome = merge_yaml(ours, theirs)?;
+
+        assert_eq!(
+            outcome.conflicts,
+            vec!["jobs.check.runs-on", "jobs.check.steps[name=checkout].uses"]
+        );
+        // Conflicting values are kept as they are and the file is untouched
+        assert_eq!(outcome.content, ours);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_yaml_plain_sequences() -> Result<(), MergeError> {
+        let ours = "check:\n  script:\n    - make\nbranches:\n  - main\n  - develop\n";
+        let theirs = "check:\n  script:\n    - nix flake check\nbranches:\n  - main\n";
+
+        let outcome = merge_yaml(ours, theirs)?;
+
+        assert!(outcome.conflicts.is_empty());
+        assert_eq!(
+            outcome.content,
+            "check:\n  script:\n    - make\n    - nix flake check\nbranches:\n  - main\n  - develop\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_yaml_empty_file() -> Result<(), MergeError> {
+        let theirs = "a: 1\n";
+        assert_eq!(merge_yaml("", theirs)?.content, theirs);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_yaml_idempotent() -> Result<(), MergeError> {
+        let ours = "a:\n  b: 1\n";
+        let theirs = "a:\n  c: 2\nd: [1, 2]\n";
+
+        let merged = merge_yaml(ours, theirs)?.content;
+        let merged_again = merge_yaml(&merged, theirs)?.content;
+        assert_eq!(merged, merged_again);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_yaml_invalid() {
+        assert!(matches!(
+            merge_yaml("a: [", "a: 1"),
+            Err(MergeError::YamlParseError(_))
+        ));
+    }
+}
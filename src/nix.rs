@@ -1,5 +1,5 @@
 use color_eyre::eyre::Result;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use thiserror::Error;
 
@@ -22,9 +22,10 @@ pub trait NixCmdInterface {
     // TODO figure out how to remove the static lifetime
     type Error: From<NixCmdInterfaceError> + std::error::Error + Send + Sync + 'static;
 
-    fn eval_nix_file(&self, path: &PathBuf, to_json: bool) -> Result<String, Self::Error>;
+    fn eval_nix_file(&self, path: &Path, to_json: bool) -> Result<String, Self::Error>;
     fn store_path_of_flake(&self, flake_uri: &str) -> Result<PathBuf, Self::Error>;
-    fn nixfmt_file(&self, path: &PathBuf) -> Result<(), Self::Error>;
+    fn nixfmt_file(&self, path: &Path) -> Result<(), Self::Error>;
+    fn fetch_url(&self, url: &str) -> Result<PathBuf, Self::Error>;
 }
 
 pub struct NixExecutor {
@@ -88,9 +89,9 @@ impl NixExecutor {
 impl NixCmdInterface for NixExecutor {
     type Error = NixExecutorError;
 
-    fn eval_nix_file(&self, path: &PathBuf, to_json: bool) -> Result<String, Self::Error> {
+    fn eval_nix_file(&self, path: &Path, to_json: bool) -> Result<String, Self::Error> {
         let path = path.to_str().ok_or(NixExecutorError::NixCmdInterfaceError(
-            NixCmdInterfaceError::InvalidPath(path.clone()),
+            NixCmdInterfaceError::InvalidPath(path.to_path_buf()),
         ))?;
 
         let mut command = self.nix_command();
@@ -146,9 +147,9 @@ impl NixCmdInterface for NixExecutor {
         Ok(PathBuf::from(stdout.trim()))
     }
 
-    fn nixfmt_file(&self, path: &PathBuf) -> Result<(), Self::Error> {
+    fn nixfmt_file(&self, path: &Path) -> Result<(), Self::Error> {
         let path = path.to_str().ok_or(NixExecutorError::NixCmdInterfaceError(
-            NixCmdInterfaceError::InvalidPath(path.clone()),
+            NixCmdInterfaceError::InvalidPath(path.to_path_buf()),
         ))?;
 
         let output = self.nixfmt_command().arg(path).output().map_err(|e| {
@@ -165,6 +166,38 @@ impl NixCmdInterface for NixExecutor {
 
         Ok(())
     }
+
+    /// Downloads the file at `url` into the nix store, returning its
+    /// store path.
+    fn fetch_url(&self, url: &str) -> Result<PathBuf, Self::Error> {
+        let mut command = self.nix_command();
+        command.args(["store", "prefetch-file", "--json", url]);
+
+        let output = command.output().map_err(|e| {
+            NixExecutorError::NixCmdInterfaceError(NixCmdInterfaceError::NixCommandError(
+                e.to_string(),
+            ))
+        })?;
+
+        if !output.status.success() {
+            return Err(NixExecutorError::NonzeroStatusError(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            ));
+        }
+
+        let prefetched: serde_json::Value =
+            serde_json::from_slice(&output.stdout).map_err(|e| {
+                NixExecutorError::NixCmdInterfaceError(NixCmdInterfaceError::NixCommandError(
+                    e.to_string(),
+                ))
+            })?;
+
+        prefetched["storePath"].as_str().map(PathBuf::from).ok_or(
+            NixExecutorError::NixCmdInterfaceError(NixCmdInterfaceError::NixCommandError(
+                "missing store path in the output of nix store prefetch-file".to_string(),
+            )),
+        )
+    }
 }
 
 #[cfg(test)]
@@ -233,11 +266,11 @@ mod tests {
         impl NixCmdInterface for MockExecutor {
             type Error = NixCmdInterfaceError;
 
-            fn eval_nix_file(&self, path: &PathBuf, _to_json: bool) -> Result<String, Self::Error> {
+            fn eval_nix_file(&self, path: &Path, _to_json: bool) -> Result<String, Self::Error> {
                 self.eval_results
                     .get(path)
                     .cloned()
-                    .unwrap_or(Err(NixCmdInterfaceError::InvalidPath(path.clone())))
+                    .unwrap_or(Err(NixCmdInterfaceError::InvalidPath(path.to_path_buf())))
             }
 
             fn store_path_of_flake(&self, flake_uri: &str) -> Result<PathBuf, Self::Error> {
@@ -249,16 +282,23 @@ mod tests {
                 })
             }
 
-            fn nixfmt_file(&self, path: &PathBuf) -> Result<(), Self::Error> {
+            fn nixfmt_file(&self, path: &Path) -> Result<(), Self::Error> {
                 if path.exists() {
                     // Touch the file by updating its modification time
                     File::open(path)
                         .and_then(|file| file.set_modified(SystemTime::now()))
                         .map_err(|e| NixCmdInterfaceError::NixCommandError(e.to_string()))
                 } else {
-                    Err(NixCmdInterfaceError::InvalidPath(path.clone()))
+                    Err(NixCmdInterfaceError::InvalidPath(path.to_path_buf()))
                 }
             }
+
+            fn fetch_url(&self, url: &str) -> Result<PathBuf, Self::Error> {
+                Err(NixCmdInterfaceError::NixCommandError(format!(
+                    "URL not mocked: {}",
+                    url
+                )))
+            }
         }
 
         #[test]
@@ -1,13 +1,16 @@
 //! Provides a way to parse and store flake parts metadata
 use color_eyre::eyre::Result;
+use glob::{Pattern, PatternError};
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Component, PathBuf};
 use thiserror::Error;
 
-use crate::config::META_FILE;
+use crate::config::{META_FILE, PRESETS_DIR};
 use crate::nix::NixCmdInterface;
+use crate::presets::{load_store_presets, Preset};
 
 #[derive(Debug, Clone)]
 pub struct FlakePart {
@@ -37,6 +40,60 @@ pub fn normalize_flake_string(target: &str, flake: &str, derivation: Option<&str
     }
 }
 
+/// Separates the alias of a store from its flake uri when passed using
+/// `-I`, eg. `myco=github:myco/parts#flake-parts`
+pub static STORE_ALIAS_SEPARATOR: char = '=';
+
+/// Splits a store passed using `-I` into its optional alias and flake uri.
+/// Only names consisting of alphanumeric characters, `-` and `_` are
+/// considered aliases, so that flake uris containing `=` (eg. query
+/// parameters) are kept intact.
+pub fn parse_store_spec(spec: &str) -> (Option<&str>, &str) {
+    match spec.split_once(STORE_ALIAS_SEPARATOR) {
+        Some((alias, flake_uri))
+            if !alias.is_empty()
+                && alias
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+        {
+            (Some(alias), flake_uri)
+        }
+        _ => (None, spec),
+    }
+}
+
+/// Maps the aliases of the `stores` to their flake uris.
+pub fn store_aliases<'b>(
+    stores: impl IntoIterator<Item = &'b FlakePartsStore>,
+) -> BTreeMap<&'b str, &'b str> {
+    stores
+        .into_iter()
+        .filter_map(|store| {
+            store
+                .alias
+                .as_deref()
+                .map(|alias| (alias, store.flake_uri.as_str()))
+        })
+        .collect()
+}
+
+/// Same as `normalize_flake_string`, but references of the form
+/// `alias/part` to one of the aliased stores are resolved first.
+pub fn normalize_part_reference(
+    target: &str,
+    flake: &str,
+    aliases: &BTreeMap<&str, &str>,
+) -> String {
+    if !target.contains('#') {
+        if let Some((alias, part)) = target.split_once('/') {
+            if let Some(aliased_flake) = aliases.get(alias) {
+                return normalize_flake_string(part, aliased_flake, None);
+            }
+        }
+    }
+    normalize_flake_string(target, flake, None)
+}
+
 impl<'a> FlakePartTuple<'a> {
     pub fn new(store: &'a FlakePartsStore, part: FlakePart) -> Self {
         Self { store, part }
@@ -46,11 +103,71 @@ impl<'a> FlakePartTuple<'a> {
         normalize_flake_string(&self.part.name, &self.store.flake_uri, derivation)
     }
 
+    /// Short `alias/part` name of the part in case its store has an alias.
+    pub fn to_aliased_name(&self) -> Option<String> {
+        self.store
+            .alias
+            .as_ref()
+            .map(|alias| format!("{}/{}", alias, self.part.name))
+    }
+
+    /// Checks that the files with modes declared in `meta.nix` are
+    /// shipped by the part.
+    pub fn validate_modes(&self) -> Result<(), FileModeError> {
+        for file in self.part.metadata.modes.keys() {
+            if file.is_absolute()
+                || file
+                    .components()
+                    .any(|component| component == Component::ParentDir)
+            {
+                return Err(FileModeError::InvalidPathError(
+                    self.to_flake_uri(None),
+                    file.clone(),
+                ));
+            }
+
+            if self
+                .part
+                .nix_store_path
+                .join(file)
+                .symlink_metadata()
+                .is_err()
+            {
+                return Err(FileModeError::MissingFileError(
+                    self.to_flake_uri(None),
+                    file.clone(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `reference` is the full flake uri, the name or the aliased
+    /// name of the part.
+    pub fn is_referenced_by(&self, reference: &str) -> bool {
+        reference == self.to_flake_uri(None)
+            || reference == self.part.name
+            || self.to_aliased_name().as_deref() == Some(reference)
+    }
+
     pub fn resolve_dependencies_of(
         parts_tuples_pool: &[FlakePartTuple],
         start_indices: Vec<usize>,
+    ) -> (Vec<String>, Vec<String>) {
+        Self::resolve_dependencies_excluding(parts_tuples_pool, start_indices, |_| false)
+    }
+
+    /// Same as `resolve_dependencies_of`, but the dependencies of the
+    /// `excluded` parts aren't followed. The excluded parts themselves are
+    /// still reported as resolved dependencies.
+    pub fn resolve_dependencies_excluding(
+        parts_tuples_pool: &[FlakePartTuple],
+        start_indices: Vec<usize>,
+        excluded: impl Fn(&FlakePartTuple) -> bool,
     ) -> (Vec<String>, Vec<String>) {
         use std::collections::{HashSet, VecDeque};
+        let aliases = store_aliases(parts_tuples_pool.iter().map(|p| p.store));
         let mut resolved_dependencies = HashSet::new();
         let mut unresolved_dependencies = Vec::new();
         let mut to_process = VecDeque::from(start_indices);
@@ -58,14 +175,17 @@ impl<'a> FlakePartTuple<'a> {
         while let Some(index) = to_process.pop_front() {
             let part_tuple = &parts_tuples_pool[index];
             for dep in &part_tuple.part.metadata.dependencies {
-                let normalized_dep = normalize_flake_string(dep, &part_tuple.store.flake_uri, None);
+                let normalized_dep =
+                    normalize_part_reference(dep, &part_tuple.store.flake_uri, &aliases);
                 if resolved_dependencies.insert(normalized_dep.clone()) {
                     // If this is a new dependency, try to find the corresponding PartTuple
                     if let Some(dep_index) = parts_tuples_pool
                         .iter()
                         .position(|p| p.to_flake_uri(None) == normalized_dep)
                     {
-                        to_process.push_back(dep_index);
+                        if !excluded(&parts_tuples_pool[dep_index]) {
+                            to_process.push_back(dep_index);
+                        }
                     } else {
                         // This dependency couldn't be resolved
                         unresolved_dependencies.push(normalized_dep);
@@ -83,11 +203,12 @@ impl<'a> FlakePartTuple<'a> {
     pub fn find_conflicting_parts_in(
         parts_tuples: &'a [FlakePartTuple],
     ) -> Vec<&'a FlakePartTuple<'a>> {
+        let aliases = store_aliases(parts_tuples.iter().map(|p| p.store));
         let conflicting_parts_uris = parts_tuples
             .iter()
             .flat_map(|part_tuple| {
                 part_tuple.part.metadata.conflicts.iter().map(|conflict| {
-                    normalize_flake_string(conflict, &part_tuple.store.flake_uri, None)
+                    normalize_part_reference(conflict, &part_tuple.store.flake_uri, &aliases)
                 })
             })
             .collect::<Vec<_>>();
@@ -107,14 +228,55 @@ impl<'a> FlakePartTuple<'a> {
         required_parts
             .iter()
             .filter(|&uri| {
-                !parts_tuples.iter().any(|part_tuple| {
-                    uri == &part_tuple.to_flake_uri(None) || uri == &part_tuple.part.name
-                })
+                !parts_tuples
+                    .iter()
+                    .any(|part_tuple| part_tuple.is_referenced_by(uri))
             })
             .collect::<Vec<_>>()
     }
 }
 
+/// Selection of parts passed by the user, either an exact part name (or
+/// its full flake uri or `alias/part` name) or a glob pattern matched
+/// against all of them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PartSelector {
+    Exact(String),
+    Glob(Pattern),
+}
+
+impl PartSelector {
+    pub fn parse(selector: &str) -> Result<Self, PatternError> {
+        if selector.contains(['*', '?', '[']) {
+            Ok(Self::Glob(Pattern::new(selector)?))
+        } else {
+            Ok(Self::Exact(selector.to_string()))
+        }
+    }
+
+    pub fn matches(&self, part_tuple: &FlakePartTuple) -> bool {
+        match self {
+            Self::Exact(selector) => part_tuple.is_referenced_by(selector),
+            Self::Glob(pattern) => {
+                pattern.matches(&part_tuple.to_flake_uri(None))
+                    || pattern.matches(&part_tuple.part.name)
+                    || part_tuple
+                        .to_aliased_name()
+                        .is_some_and(|name| pattern.matches(&name))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for PartSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exact(selector) => write!(f, "{}", selector),
+            Self::Glob(pattern) => write!(f, "{}", pattern),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FlakePartMetadata {
     #[serde(default)]
@@ -134,13 +296,62 @@ pub struct FlakePartMetadata {
 
     #[serde(rename = "extraSubstituters", default)]
     pub extra_substituters: Vec<String>,
+
+    #[serde(default)]
+    pub hooks: Vec<FlakePartHook>,
+
+    /// Explicit modes of files relative to the root of the part
+    #[serde(default)]
+    pub modes: BTreeMap<PathBuf, FileMode>,
+}
+
+/// Unix file mode declared as an octal string in `meta.nix`, eg. `"0600"`,
+/// as nix doesn't have octal literals. Only the permission bits are kept.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct FileMode(pub u32);
+
+impl TryFrom<String> for FileMode {
+    type Error = std::num::ParseIntError;
+
+    fn try_from(mode: String) -> Result<Self, Self::Error> {
+        u32::from_str_radix(mode.trim_start_matches("0o"), 8).map(|mode| Self(mode & 0o7777))
+    }
+}
+
+impl From<FileMode> for String {
+    fn from(mode: FileMode) -> Self {
+        format!("{:04o}", mode.0)
+    }
+}
+
+/// Shell command that should be run in the target directory after
+/// the part was applied, eg. `direnv allow`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FlakePartHook {
+    #[serde(default)]
+    pub description: String,
+
+    pub command: String,
 }
 
 #[derive(Debug)]
 pub struct FlakePartsStore {
     pub flake_uri: String,
+    /// Short name given to the store using `-I alias=flake_uri`
+    pub alias: Option<String>,
     pub nix_store_path: PathBuf,
     pub parts: Vec<FlakePart>,
+    pub presets: BTreeMap<String, Preset>,
+}
+
+#[derive(Error, Debug)]
+pub enum FileModeError {
+    #[error("Part {0} declares a mode of {1:?}, which isn't a relative path inside of the part")]
+    InvalidPathError(String, PathBuf),
+
+    #[error("Part {0} declares a mode of {1:?}, which isn't a file of the part")]
+    MissingFileError(String, PathBuf),
 }
 
 #[derive(Error, Debug)]
@@ -196,16 +407,27 @@ pub enum FlakePartsStoreParseError {
 }
 
 impl FlakePartsStore {
-    fn new(flake_uri: String, nix_store_path: PathBuf, parts: Vec<FlakePart>) -> Self {
+    fn new(
+        flake_uri: String,
+        alias: Option<String>,
+        nix_store_path: PathBuf,
+        parts: Vec<FlakePart>,
+        presets: BTreeMap<String, Preset>,
+    ) -> Self {
         Self {
             flake_uri,
+            alias,
             nix_store_path,
             parts,
+            presets,
         }
     }
 
     // TODO handle errors
+    /// Loads the store, `flake_uri` can be optionally prefixed with an
+    /// alias, see `parse_store_spec`.
     pub fn from_flake_uri(flake_uri: &str, nix_cmd: &impl NixCmdInterface) -> Result<Self> {
+        let (alias, flake_uri) = parse_store_spec(flake_uri);
         let nix_store_path = nix_cmd.store_path_of_flake(flake_uri)?;
 
         let parts = fs::read_dir(nix_store_path.join("flake-parts"))?
@@ -216,7 +438,15 @@ impl FlakePartsStore {
             })
             .collect::<Result<_>>()?;
 
-        Ok(Self::new(flake_uri.to_string(), nix_store_path, parts))
+        let presets = load_store_presets(&nix_store_path.join(PRESETS_DIR), nix_cmd)?;
+
+        Ok(Self::new(
+            flake_uri.to_string(),
+            alias.map(str::to_string),
+            nix_store_path,
+            parts,
+            presets,
+        ))
     }
 }
 
@@ -285,4 +515,191 @@ mod tests {
         let result = normalize_flake_string("output#extra", "github:user/repo#branch", None);
         assert_eq!(result, "output#extra");
     }
+
+    #[test]
+    fn test_metadata_modes() {
+        let metadata: FlakePartMetadata =
+            serde_json::from_str(r#"{"modes": {"secrets/secret.age": "0600", "bin/run": "755"}}"#)
+                .unwrap();
+
+        assert_eq!(
+            metadata.modes,
+            BTreeMap::from([
+                (PathBuf::from("bin/run"), FileMode(0o755)),
+                (PathBuf::from("secrets/secret.age"), FileMode(0o600)),
+            ])
+        );
+        assert!(serde_json::from_str::<FlakePartMetadata>(r#"{"modes": {"a": "rwx"}}"#).is_err());
+
+        let metadata: FlakePartMetadata =
+            serde_json::from_str(r#"{"modes": {"a": "170755"}}"#).unwrap();
+        assert_eq!(metadata.modes[&PathBuf::from("a")], FileMode(0o755));
+    }
+
+    #[test]
+    fn test_validate_modes() {
+        let part_dir = tempfile::TempDir::new().unwrap();
+        fs::create_dir(part_dir.path().join("bin")).unwrap();
+        fs::write(part_dir.path().join("bin/run"), "").unwrap();
+
+        let store = FlakePartsStore::new(
+            "github:org/store#flake-parts".to_string(),
+            None,
+            PathBuf::new(),
+            Vec::new(),
+            BTreeMap::new(),
+        );
+        let part_tuple = |file: &str| {
+            let metadata = serde_json::json!({ "modes": { file: "0755" } });
+            FlakePartTuple::new(
+                &store,
+                FlakePart::new(
+                    "scripts".to_string(),
+                    part_dir.path().to_path_buf(),
+                    serde_json::from_value(metadata).unwrap(),
+                ),
+            )
+        };
+
+        assert!(part_tuple("bin/run").validate_modes().is_ok());
+        assert!(part_tuple("./bin/run").validate_modes().is_ok());
+        assert!(matches!(
+            part_tuple("../../etc/passwd").validate_modes(),
+            Err(FileModeError::InvalidPathError(..))
+        ));
+        assert!(matches!(
+            part_tuple("/etc/passwd").validate_modes(),
+            Err(FileModeError::InvalidPathError(..))
+        ));
+        assert!(matches!(
+            part_tuple("bin/missing").validate_modes(),
+            Err(FileModeError::MissingFileError(uri, _)) if uri == "github:org/store#flake-parts/scripts"
+        ));
+    }
+
+    fn store(parts: &[(&str, &[&str])]) -> FlakePartsStore {
+        let parts = parts
+            .iter()
+            .map(|(name, dependencies)| {
+                let metadata = serde_json::json!({ "dependencies": dependencies });
+                FlakePart::new(
+                    name.to_string(),
+                    PathBuf::from(name),
+                    serde_json::from_value(metadata).unwrap(),
+                )
+            })
+            .collect();
+
+        FlakePartsStore::new(
+            "github:org/store#flake-parts".to_string(),
+            None,
+            PathBuf::new(),
+            parts,
+            BTreeMap::new(),
+        )
+    }
+
+    #[test]
+    fn test_part_selector() {
+        let store = store(&[("gh-actions-check", &[]), ("treefmt", &[])]);
+        let tuples = store
+            .parts
+            .iter()
+            .map(|part| FlakePartTuple::new(&store, part.to_owned()))
+            .collect::<Vec<_>>();
+
+        let glob = PartSelector::parse("gh-actions-*").unwrap();
+        assert!(glob.matches(&tuples[0]));
+        assert!(!glob.matches(&tuples[1]));
+
+        let uri_glob = PartSelector::parse("github:org/store#flake-parts/*").unwrap();
+        assert!(tuples.iter().all(|tuple| uri_glob.matches(tuple)));
+
+        let exact = PartSelector::parse("github:org/store#flake-parts/treefmt").unwrap();
+        assert_eq!(
+            exact,
+            PartSelector::Exact("github:org/store#flake-parts/treefmt".to_string())
+        );
+        assert!(exact.matches(&tuples[1]));
+
+        assert!(PartSelector::parse("[gh").is_err());
+    }
+
+    #[test]
+    fn test_parse_store_spec() {
+        assert_eq!(
+            parse_store_spec("myco=github:myco/parts#flake-parts"),
+            (Some("myco"), "github:myco/parts#flake-parts")
+        );
+        assert_eq!(
+            parse_store_spec("github:myco/parts#flake-parts"),
+            (None, "github:myco/parts#flake-parts")
+        );
+        assert_eq!(
+            parse_store_spec("git+https://example.com/parts?ref=main#flake-parts"),
+            (None, "git+https://example.com/parts?ref=main#flake-parts")
+        );
+    }
+
+    #[test]
+    fn test_store_aliases() {
+        let mut myco = store(&[("treefmt", &[])]);
+        myco.alias = Some("myco".to_string());
+        myco.flake_uri = "github:myco/parts#flake-parts".to_string();
+        let local = store(&[("shells", &["myco/treefmt"])]);
+
+        let myco_tuple = FlakePartTuple::new(&myco, myco.parts[0].to_owned());
+        assert_eq!(
+            myco_tuple.to_aliased_name().as_deref(),
+            Some("myco/treefmt")
+        );
+        assert!(PartSelector::parse("myco/treefmt")
+            .unwrap()
+            .matches(&myco_tuple));
+        assert!(PartSelector::parse("myco/*").unwrap().matches(&myco_tuple));
+
+        let tuples = vec![
+            FlakePartTuple::new(&local, local.parts[0].to_owned()),
+            myco_tuple,
+        ];
+        let (resolved, unresolved) = FlakePartTuple::resolve_dependencies_of(&tuples, vec![0]);
+        assert!(unresolved.is_empty());
+        assert_eq!(resolved, vec!["github:myco/parts#flake-parts/treefmt"]);
+
+        let aliases = store_aliases([&myco, &local]);
+        assert_eq!(
+            normalize_part_reference("other/treefmt", &local.flake_uri, &aliases),
+            "github:org/store#flake-parts/other/treefmt"
+        );
+    }
+
+    #[test]
+    fn test_resolve_dependencies_excluding() {
+        let store = store(&[
+            ("+github", &["gh-actions-check", "gh-dependabot"]),
+            ("gh-actions-check", &[]),
+            ("gh-dependabot", &["gh-labels"]),
+            ("gh-labels", &[]),
+        ]);
+        let tuples = store
+            .parts
+            .iter()
+            .map(|part| FlakePartTuple::new(&store, part.to_owned()))
+            .collect::<Vec<_>>();
+
+        let (mut resolved, unresolved) =
+            FlakePartTuple::resolve_dependencies_excluding(&tuples, vec![0], |tuple| {
+                tuple.part.name == "gh-dependabot"
+            });
+        resolved.sort();
+
+        assert!(unresolved.is_empty());
+        assert_eq!(
+            resolved,
+            vec![
+                "github:org/store#flake-parts/gh-actions-check",
+                "github:org/store#flake-parts/gh-dependabot",
+            ]
+        );
+    }
 }
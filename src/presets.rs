@@ -0,0 +1,131 @@
+//! Named collections of parts referenced as `@name`, defined either in the
+//! user configuration, in the project manifest or in the `presets/`
+//! directory of a parts store.
+use color_eyre::eyre::Result;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::cmd::init::InitStrategy;
+use crate::nix::NixCmdInterface;
+
+pub static PRESET_PREFIX: char = '@';
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(from = "PresetDefinition")]
+pub struct Preset {
+    pub description: String,
+
+    /// Parts of the preset, relative to the store for store presets
+    pub parts: Vec<String>,
+
+    /// Default strategy used when the preset is selected
+    pub strategy: Option<InitStrategy>,
+
+    /// Default url of the `nixpkgs` input used when the preset is selected
+    pub nixpkgs: Option<String>,
+}
+
+/// Presets can be defined either as a plain list of parts or as a table
+/// that also carries defaults for the other options.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PresetDefinition {
+    Parts(Vec<String>),
+    #[serde(rename_all = "kebab-case")]
+    Full {
+        #[serde(default)]
+        description: String,
+        parts: Vec<String>,
+        strategy: Option<InitStrategy>,
+        nixpkgs: Option<String>,
+    },
+}
+
+impl From<PresetDefinition> for Preset {
+    fn from(definition: PresetDefinition) -> Self {
+        match definition {
+            PresetDefinition::Parts(parts) => Self {
+                parts,
+                ..Default::default()
+            },
+            PresetDefinition::Full {
+                description,
+                parts,
+                strategy,
+                nixpkgs,
+            } => Self {
+                description,
+                parts,
+                strategy,
+                nixpkgs,
+            },
+        }
+    }
+}
+
+/// Returns the name of the preset referenced by `part`, if it's a preset
+/// reference at all.
+pub fn preset_name(part: &str) -> Option<&str> {
+    part.strip_prefix(PRESET_PREFIX)
+}
+
+/// Loads the presets from the `presets/` directory of a store, each preset
+/// is a `<name>.nix` file evaluating to a preset definition.
+pub fn load_store_presets(
+    presets_dir: &Path,
+    nix_cmd: &impl NixCmdInterface,
+) -> Result<BTreeMap<String, Preset>> {
+    let mut presets = BTreeMap::new();
+    if !presets_dir.is_dir() {
+        return Ok(presets);
+    }
+
+    for entry in fs::read_dir(presets_dir)? {
+        let path = entry?.path();
+        let Some(name) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(".nix"))
+        else {
+            continue;
+        };
+
+        let eval_output = nix_cmd.eval_nix_file(&path, true)?;
+        presets.insert(name.to_string(), serde_json::from_str(&eval_output)?);
+    }
+
+    Ok(presets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preset_definitions() -> Result<()> {
+        let presets: BTreeMap<String, Preset> = toml::from_str(
+            r#"
+rust-service = ["treefmt", "shells"]
+
+[docs]
+description = "Documentation tooling"
+parts = ["mdbook"]
+strategy = "merge"
+"#,
+        )?;
+
+        assert_eq!(presets["rust-service"].parts, vec!["treefmt", "shells"]);
+        assert_eq!(presets["rust-service"].strategy, None);
+        assert_eq!(presets["docs"].description, "Documentation tooling");
+        assert_eq!(presets["docs"].strategy, Some(InitStrategy::Merge));
+        Ok(())
+    }
+
+    #[test]
+    fn test_preset_name() {
+        assert_eq!(preset_name("@rust-service"), Some("rust-service"));
+        assert_eq!(preset_name("treefmt"), None);
+    }
+}
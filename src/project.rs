@@ -0,0 +1,91 @@
+//! Discovery of already initialized flake-parts projects.
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::config::STATE_DIR;
+
+#[derive(Error, Debug)]
+pub enum ProjectRootError {
+    #[error("No flake-parts project was found in {0:?} or any of its parent directories. A project needs a `flake.nix` file and a `flake-parts/` directory, you can create one using the `init` command.")]
+    NotAProjectError(PathBuf),
+
+    #[error("The flake at {0:?} doesn't look like a flake-parts project, as it has neither a `flake-parts/` directory nor a `{STATE_DIR}/` directory. You can use the `init` command to bootstrap it.")]
+    NotAFlakePartsProjectError(PathBuf),
+}
+
+/// Whether `dir` looks like the root of a flake-parts project.
+pub fn is_project_root(dir: &Path) -> bool {
+    dir.join("flake.nix").is_file()
+        && (dir.join("flake-parts").is_dir() || dir.join(STATE_DIR).is_dir())
+}
+
+/// Finds the root of the flake-parts project containing `start` by walking
+/// upward. The nearest directory with a `flake.nix` is considered to be
+/// the root, as nested flakes are separate projects.
+pub fn find_project_root(start: &Path) -> Result<PathBuf, ProjectRootError> {
+    for dir in start.ancestors() {
+        if !dir.join("flake.nix").is_file() {
+            continue;
+        }
+
+        if is_project_root(dir) {
+            return Ok(dir.to_path_buf());
+        }
+        return Err(ProjectRootError::NotAFlakePartsProjectError(
+            dir.to_path_buf(),
+        ));
+    }
+
+    Err(ProjectRootError::NotAProjectError(start.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_find_project_root() -> std::io::Result<()> {
+        let root = TempDir::new()?;
+        let nested = root.path().join("flake-parts").join("pkgs");
+        fs::create_dir_all(&nested)?;
+        fs::write(root.path().join("flake.nix"), "{ }")?;
+
+        assert_eq!(find_project_root(root.path()).unwrap(), root.path());
+        assert_eq!(find_project_root(&nested).unwrap(), root.path());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_project_root_state_dir() -> std::io::Result<()> {
+        let root = TempDir::new()?;
+        fs::create_dir_all(root.path().join(STATE_DIR))?;
+        fs::write(root.path().join("flake.nix"), "{ }")?;
+
+        assert_eq!(find_project_root(root.path()).unwrap(), root.path());
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_project_root_errors() -> std::io::Result<()> {
+        let root = TempDir::new()?;
+        let nested = root.path().join("nested");
+        fs::create_dir_all(&nested)?;
+
+        assert!(matches!(
+            find_project_root(&nested),
+            Err(ProjectRootError::NotAProjectError(_))
+        ));
+
+        // NOTE a plain flake without flake-parts stops the discovery
+        fs::create_dir_all(root.path().join("flake-parts"))?;
+        fs::write(root.path().join("flake.nix"), "{ }")?;
+        fs::write(nested.join("flake.nix"), "{ }")?;
+        assert!(matches!(
+            find_project_root(&nested),
+            Err(ProjectRootError::NotAFlakePartsProjectError(_))
+        ));
+        Ok(())
+    }
+}
@@ -1,20 +1,66 @@
 use color_eyre::eyre::Result;
 use serde::Serialize;
 use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use thiserror::Error;
 
 use minijinja::{context, Environment};
 
-use crate::config::{FLAKE_INPUTS_TEMPLATE, FLAKE_TEMPLATE};
-use crate::parts::FlakePartMetadata;
+use crate::config::{
+    DEFAULT_NIXPKGS_URL, FLAKE_INPUTS_TEMPLATE, FLAKE_TEMPLATE, GENERATED_REGIONS,
+};
+use crate::parts::{FlakePartMetadata, FlakePartTuple};
 
 #[derive(Debug, Serialize)]
 pub struct FlakeInputsContext {
     pub inputs: JsonValue,
+
+    /// Flake uris of the parts requesting each of the inputs
+    pub sources: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Error, Debug)]
+pub enum RegionError {
+    #[error("None of the generated regions were found in flake.nix, it was most likely created by an older version. Please wrap the generated inputs in `# --- BEGIN GENERATED INPUTS ---` and `# --- END GENERATED INPUTS ---` comments first.")]
+    NoRegionsError,
+}
+
+/// Collects the flake uris of the parts requesting each of the inputs.
+fn input_sources(parts_tuples: &[FlakePartTuple]) -> BTreeMap<String, Vec<String>> {
+    let mut sources: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for part_tuple in parts_tuples {
+        if let JsonValue::Object(inputs) = &part_tuple.part.metadata.inputs {
+            for input_name in inputs.keys() {
+                sources
+                    .entry(input_name.clone())
+                    .or_default()
+                    .push(part_tuple.to_flake_uri(None));
+            }
+        }
+    }
+
+    sources
 }
 
 impl FlakeInputsContext {
     fn new(inputs: JsonValue) -> Self {
-        Self { inputs }
+        Self {
+            inputs,
+            sources: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_parts_tuples(parts_tuples: &[FlakePartTuple]) -> Self {
+        let metadata = parts_tuples
+            .iter()
+            .map(|part_tuple| &part_tuple.part.metadata)
+            .collect::<Vec<_>>();
+
+        let mut context = Self::from_merged_metadata(&metadata);
+        context.sources = input_sources(parts_tuples);
+        context
     }
 
     pub fn from_merged_metadata(metadata: &[&FlakePartMetadata]) -> Self {
@@ -49,6 +95,7 @@ pub struct FlakeContext {
     pub flake_inputs_context: FlakeInputsContext,
     pub extra_trusted_public_keys: Vec<String>,
     pub extra_substituters: Vec<String>,
+    pub nixpkgs_url: String,
 }
 
 impl FlakeContext {
@@ -61,6 +108,7 @@ impl FlakeContext {
             flake_inputs_context,
             extra_trusted_public_keys,
             extra_substituters,
+            nixpkgs_url: DEFAULT_NIXPKGS_URL.to_string(),
         }
     }
 
@@ -84,6 +132,22 @@ impl FlakeContext {
         )
     }
 
+    pub fn from_parts_tuples(parts_tuples: &[FlakePartTuple]) -> Self {
+        let metadata = parts_tuples
+            .iter()
+            .map(|part_tuple| &part_tuple.part.metadata)
+            .collect::<Vec<_>>();
+
+        let mut context = Self::from_merged_metadata(&metadata);
+        context.flake_inputs_context.sources = input_sources(parts_tuples);
+        context
+    }
+
+    pub fn with_nixpkgs_url(mut self, nixpkgs_url: &str) -> Self {
+        self.nixpkgs_url = nixpkgs_url.to_string();
+        self
+    }
+
     pub fn render(&self) -> Result<String> {
         let mut env = Environment::new();
         env.add_template("flake.nix", FLAKE_TEMPLATE).unwrap();
@@ -95,6 +159,76 @@ impl FlakeContext {
     }
 }
 
+fn region_markers(name: &str) -> (String, String) {
+    (
+        format!("# --- BEGIN GENERATED {} ---", name),
+        format!("# --- END GENERATED {} ---", name),
+    )
+}
+
+/// Finds the byte range of the lines between the begin and end markers
+/// of the generated region `name`.
+pub fn find_region(text: &str, name: &str) -> Option<Range<usize>> {
+    let (begin_marker, end_marker) = region_markers(name);
+    let mut start = None;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        match start {
+            None if line.trim() == begin_marker => start = Some(offset + line.len()),
+            Some(start) if line.trim() == end_marker => return Some(start..offset),
+            _ => {}
+        }
+        offset += line.len();
+    }
+
+    None
+}
+
+/// Replaces the contents of all generated regions of `ours` with the ones
+/// from `rendered`, leaving everything else untouched. Commented out lines
+/// (eg. binary caches) that were uncommented in `ours` stay uncommented.
+pub fn sync_regions(ours: &str, rendered: &str) -> Result<String, RegionError> {
+    let mut synced = ours.to_string();
+    let mut found = false;
+
+    for name in GENERATED_REGIONS {
+        let (Some(our_range), Some(their_range)) =
+            (find_region(&synced, name), find_region(rendered, name))
+        else {
+            log::warn!("Generated region {} wasn't found, skipping it", name);
+            continue;
+        };
+        found = true;
+
+        let our_lines = synced[our_range.clone()]
+            .lines()
+            .map(str::trim)
+            .collect::<Vec<_>>();
+
+        let region = rendered[their_range]
+            .split_inclusive('\n')
+            .map(|line| {
+                let trimmed = line.trim();
+                match trimmed.strip_prefix("# ") {
+                    Some(uncommented) if our_lines.contains(&uncommented) => {
+                        line.replacen(trimmed, uncommented, 1)
+                    }
+                    _ => line.to_string(),
+                }
+            })
+            .collect::<String>();
+
+        synced.replace_range(our_range, &region);
+    }
+
+    if !found {
+        Err(RegionError::NoRegionsError)?
+    }
+
+    Ok(synced)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -116,6 +250,8 @@ mod tests {
             conflicts: vec![],
             extra_trusted_public_keys: vec![],
             extra_substituters: vec![],
+            hooks: vec![],
+            modes: Default::default(),
         };
         let metadata2 = FlakePartMetadata {
             description: "Metadata 2".to_string(),
@@ -124,6 +260,8 @@ mod tests {
             conflicts: vec![],
             extra_trusted_public_keys: vec![],
             extra_substituters: vec![],
+            hooks: vec![],
+            modes: Default::default(),
         };
         let metadata = vec![&metadata1, &metadata2];
 
@@ -210,6 +348,8 @@ mod tests {
             conflicts: vec![],
             extra_trusted_public_keys: vec!["key1".to_string()],
             extra_substituters: vec!["sub1".to_string()],
+            hooks: vec![],
+            modes: Default::default(),
         };
         let metadata2 = FlakePartMetadata {
             description: "Metadata 2".to_string(),
@@ -218,6 +358,8 @@ mod tests {
             conflicts: vec![],
             extra_trusted_public_keys: vec!["key2".to_string()],
             extra_substituters: vec!["sub2".to_string()],
+            hooks: vec![],
+            modes: Default::default(),
         };
         let metadata = vec![&metadata1, &metadata2];
 
@@ -261,25 +403,33 @@ mod tests {
               flake-parts.url = "github:hercules-ci/flake-parts";
 
               # --- YOUR DEPENDENCIES ---
+              # --- BEGIN GENERATED INPUTS ---
               input1.url = "github:org1/repo1";
               input2.url = "github:org2/repo2";
+              # --- END GENERATED INPUTS ---
             };
 
             # NOTE Here you can add additional binary cache substituers that you trust.
             # There are also some sensible default caches commented out that you
             # might consider using, however, you are advised to doublecheck the keys.
+            # The entries between the GENERATED markers are regenerated by the `sync`
+            # command, uncommented entries stay uncommented.
             nixConfig = {
               extra-trusted-public-keys = [
                 # "cache.nixos.org-1:6NCHdD59X431o0gWypbMrAURkbJ16ZPMQFGspcDShjY="
                 # "nix-community.cachix.org-1:mB9FSh9qf2dCimDSUo8Zy7bkq5CX+/rkCWyvRCYg3Fs="
+                # --- BEGIN GENERATED TRUSTED-PUBLIC-KEYS ---
                 # "key1"
                 # "key2"
+                # --- END GENERATED TRUSTED-PUBLIC-KEYS ---
                 ];
               extra-substituters = [
                 # "https://cache.nixos.org"
                 # "https://nix-community.cachix.org/"
+                # --- BEGIN GENERATED SUBSTITUTERS ---
                 # "sub1"
                 # "sub2"
+                # --- END GENERATED SUBSTITUTERS ---
                 ];
             };
 
@@ -322,4 +472,90 @@ mod tests {
         assert_eq!(cleaned_rendered, cleaned_expected);
         Ok(())
     }
+
+    #[test]
+    fn test_flake_inputs_context_render_with_sources() -> Result<()> {
+        let mut context = FlakeInputsContext::new(json!({"input1": {
+            "url": "github:org1/repo1",
+        }}));
+        context.sources.insert(
+            "input1".to_string(),
+            vec!["store#parts/a".to_string(), "store#parts/b".to_string()],
+        );
+
+        assert_eq!(
+            context.render()?.trim(),
+            "# required by store#parts/a, store#parts/b\n    input1.url = \"github:org1/repo1\";"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_region() {
+        let text =
+            "a\n  # --- BEGIN GENERATED INPUTS ---\n  b\n  # --- END GENERATED INPUTS ---\nc\n";
+
+        let range = find_region(text, "INPUTS").unwrap();
+        assert_eq!(&text[range], "  b\n");
+        assert_eq!(find_region(text, "SUBSTITUTERS"), None);
+    }
+
+    #[test]
+    fn test_sync_regions() -> Result<(), RegionError> {
+        let ours = r#"{
+  inputs = {
+    my-input.url = "github:me/my-input";
+    # --- BEGIN GENERATED INPUTS ---
+    old.url = "github:org/old";
+    # --- END GENERATED INPUTS ---
+  };
+  nixConfig.extra-substituters = [
+    "https://my.cache"
+    # --- BEGIN GENERATED SUBSTITUTERS ---
+    "https://enabled.cache"
+    # "https://removed.cache"
+    # --- END GENERATED SUBSTITUTERS ---
+  ];
+}
+"#;
+        let rendered = r#"{
+  inputs = {
+    # --- BEGIN GENERATED INPUTS ---
+    # required by store#parts/new
+    new.url = "github:org/new";
+    # --- END GENERATED INPUTS ---
+  };
+  nixConfig.extra-substituters = [
+    # --- BEGIN GENERATED SUBSTITUTERS ---
+    # "https://enabled.cache"
+    # "https://new.cache"
+    # --- END GENERATED SUBSTITUTERS ---
+  ];
+}
+"#;
+
+        assert_eq!(
+            sync_regions(ours, rendered)?,
+            r#"{
+  inputs = {
+    my-input.url = "github:me/my-input";
+    # --- BEGIN GENERATED INPUTS ---
+    # required by store#parts/new
+    new.url = "github:org/new";
+    # --- END GENERATED INPUTS ---
+  };
+  nixConfig.extra-substituters = [
+    "https://my.cache"
+    # --- BEGIN GENERATED SUBSTITUTERS ---
+    "https://enabled.cache"
+    # "https://new.cache"
+    # --- END GENERATED SUBSTITUTERS ---
+  ];
+}
+"#
+        );
+
+        assert!(sync_regions("{ }", rendered).is_err());
+        Ok(())
+    }
 }
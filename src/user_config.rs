@@ -0,0 +1,234 @@
+//! User-level configuration stored at
+//! `$XDG_CONFIG_HOME/flake-parts-builder/config.toml` providing defaults
+//! for the command line arguments.
+//!
+//! Options passed on the command line (or using their `FLAKE_PARTS_BUILDER_*`
+//! environment variables) take precedence over the configuration file, which
+//! in turn takes precedence over the built-in defaults. Stores from the
+//! configuration file are always loaded in addition to the ones passed
+//! using `-I`.
+use color_eyre::eyre::Result;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::cmd::add::AddCommand;
+use crate::cmd::init::{InitCommand, InitStrategy};
+use crate::cmd::SharedArgs;
+use crate::config::{USER_CONFIG_DIR, USER_CONFIG_ENV, USER_CONFIG_FILE};
+use crate::presets::Preset;
+
+#[derive(Error, Debug)]
+pub enum UserConfigError {
+    #[error("Failed to parse the user configuration {0:?}: {1}")]
+    ConfigParseError(PathBuf, String),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct UserConfig {
+    /// Parts stores loaded by every command, same as `-I`
+    #[serde(default)]
+    pub stores: Vec<String>,
+
+    #[serde(default)]
+    pub disable_base: bool,
+
+    /// Default strategy used for already existing files
+    pub strategy: Option<InitStrategy>,
+
+    /// Default url of the `nixpkgs` input of the generated `flake.nix`
+    pub nixpkgs: Option<String>,
+
+    /// Short names for parts, for example `fmt = "treefmt"`
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+
+    /// Named collections of parts usable as `@name`
+    #[serde(default)]
+    pub presets: BTreeMap<String, Preset>,
+}
+
+impl UserConfig {
+    /// Location of the configuration file, `$FLAKE_PARTS_BUILDER_CONFIG`
+    /// takes precedence over the XDG config directory.
+    pub fn path() -> Option<PathBuf> {
+        if let Some(path) = env::var_os(USER_CONFIG_ENV) {
+            return Some(PathBuf::from(path));
+        }
+
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+        Some(config_home.join(USER_CONFIG_DIR).join(USER_CONFIG_FILE))
+    }
+
+    pub fn from_toml(path: &Path, contents: &str) -> Result<Self, UserConfigError> {
+        toml::from_str(contents)
+            .map_err(|err| UserConfigError::ConfigParseError(path.to_path_buf(), err.to_string()))
+    }
+
+    /// Reads the user configuration, returns the default one if there's
+    /// no configuration file.
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path().filter(|path| path.is_file()) else {
+            log::debug!("No user configuration found");
+            return Ok(Self::default());
+        };
+
+        log::info!("Reading the user configuration {:?}", path);
+        Ok(Self::from_toml(&path, &fs::read_to_string(&path)?)?)
+    }
+
+    /// Adds the configured stores and resolves whether the base parts are
+    /// disabled, the configured value is used only if neither the flags
+    /// nor the environment decide it.
+    pub fn apply_to_shared_args(&self, shared_args: &mut SharedArgs) -> Result<()> {
+        for store in &self.stores {
+            if !shared_args.parts_stores.contains(store) {
+                shared_args.parts_stores.push(store.clone());
+            }
+        }
+        shared_args.disable_base_parts = shared_args
+            .disable_base_choice()?
+            .unwrap_or(self.disable_base);
+        Ok(())
+    }
+
+    /// Fills in the options of `cmd` that weren't passed explicitly and
+    /// expands the part aliases.
+    pub fn apply_to_init(&self, cmd: &mut InitCommand) -> Result<()> {
+        self.apply_to_shared_args(&mut cmd.shared_args)?;
+        cmd.strategy = cmd.strategy.or(self.strategy);
+        // NOTE the url recorded in the lock passed using `--from` takes
+        // precedence over the configured one
+        if cmd.from.is_none() {
+            cmd.nixpkgs = cmd.nixpkgs.take().or_else(|| self.nixpkgs.clone());
+        }
+        cmd.parts = cmd
+            .parts
+            .iter()
+            .map(|part| match part.strip_prefix('!') {
+                Some(excluded) => format!("!{}", self.resolve_alias(excluded)),
+                None => self.resolve_alias(part).to_string(),
+            })
+            .collect();
+        for (name, preset) in &self.presets {
+            cmd.presets
+                .entry(name.clone())
+                .or_insert_with(|| preset.clone());
+        }
+        Ok(())
+    }
+
+    /// Same as `apply_to_init`, but the `nixpkgs` url isn't filled in as
+    /// `add` never renders `flake.nix`.
+    pub fn apply_to_add(&self, cmd: &mut AddCommand) -> Result<()> {
+        let nixpkgs = cmd.init.nixpkgs.take();
+        self.apply_to_init(&mut cmd.init)?;
+        cmd.init.nixpkgs = nixpkgs;
+        Ok(())
+    }
+
+    pub fn resolve_alias<'a>(&'a self, part: &'a str) -> &'a str {
+        self.aliases.get(part).map_or(part, String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    fn shared_args(parts_stores: &[&str]) -> SharedArgs {
+        SharedArgs {
+            parts_stores: parts_stores.iter().map(|s| s.to_string()).collect(),
+            disable_base_parts: false,
+            enable_base_parts: false,
+            verbose: false,
+        }
+    }
+
+    #[test]
+    fn test_user_config_from_toml() -> Result<(), UserConfigError> {
+        let contents = r#"
+stores = ["github:org/store#flake-parts"]
+strategy = "merge"
+
+[aliases]
+fmt = "treefmt"
+
+[presets]
+rust-service = ["treefmt", "shells"]
+"#;
+
+        let config = UserConfig::from_toml(Path::new("config.toml"), contents)?;
+        assert_eq!(config.stores, vec!["github:org/store#flake-parts"]);
+        assert_eq!(config.strategy, Some(InitStrategy::Merge));
+        assert!(!config.disable_base);
+        assert_eq!(config.resolve_alias("fmt"), "treefmt");
+        assert_eq!(config.resolve_alias("shells"), "shells");
+        assert_eq!(
+            config.presets["rust-service"].parts,
+            vec!["treefmt", "shells"]
+        );
+
+        assert!(UserConfig::from_toml(Path::new("config.toml"), "typo = 1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_to_shared_args() -> Result<()> {
+        let config = UserConfig {
+            stores: vec!["store-a".to_string(), "store-b".to_string()],
+            disable_base: true,
+            ..Default::default()
+        };
+
+        let mut args = shared_args(&["store-b", "store-c"]);
+        config.apply_to_shared_args(&mut args)?;
+        assert_eq!(args.parts_stores, vec!["store-b", "store-c", "store-a"]);
+        assert!(args.disable_base_parts);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_to_shared_args_enable_base() -> Result<()> {
+        let config = UserConfig {
+            disable_base: true,
+            ..Default::default()
+        };
+
+        let mut args = shared_args(&[]);
+        args.enable_base_parts = true;
+        config.apply_to_shared_args(&mut args)?;
+        assert!(!args.disable_base_parts);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disable_base_choice() -> Result<()> {
+        let env_true = Some(OsStr::new("true"));
+
+        let mut args = shared_args(&[]);
+        assert_eq!(args.disable_base_choice_with(None)?, None);
+        assert_eq!(args.disable_base_choice_with(env_true)?, Some(true));
+        assert_eq!(
+            args.disable_base_choice_with(Some(OsStr::new("0")))?,
+            Some(false)
+        );
+        assert!(args
+            .disable_base_choice_with(Some(OsStr::new("maybe")))
+            .is_err());
+
+        // NOTE the flag takes precedence over the environment variable
+        args.enable_base_parts = true;
+        assert_eq!(args.disable_base_choice_with(env_true)?, Some(false));
+        Ok(())
+    }
+}